@@ -0,0 +1,286 @@
+//! Converting files in place, in bulk.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::encoding::*;
+use crate::io::ReadChunks;
+use crate::pipeline::Pipeline;
+
+/// Which files to convert, and how.
+#[derive(Debug, Clone, Default)]
+pub struct BatchOptions {
+    /// Only convert files matching at least one of these globs. If empty, all files are included.
+    pub include: Vec<String>,
+
+    /// Don't convert files matching any of these globs, or descend into directories matching them.
+    pub exclude: Vec<String>,
+
+    /// If set, keep the original of each converted file, with this suffix added to its name.
+    pub backup_suffix: Option<String>,
+}
+
+impl BatchOptions {
+    /// Check a path against the include and exclude globs. Globs without a '/' are matched
+    /// against the file name; others against the path relative to the directory being searched.
+    fn matches(&self, relative: &Path, is_dir: bool) -> bool {
+        let path = relative.to_string_lossy().replace('\\', "/");
+        let name = path.rsplit('/').next().unwrap_or("");
+        let matches_any = |globs: &[String]| globs.iter().any(|glob| {
+            glob_match(glob, if glob.contains('/') { &path } else { name })
+        });
+        if matches_any(&self.exclude) {
+            return false;
+        }
+        is_dir || self.include.is_empty() || matches_any(&self.include)
+    }
+}
+
+/// Match text against a shell-style glob: `?` matches any character but '/', `*` any run of
+/// characters but '/', `**` anything at all, and `[...]` any one of a set of characters, which
+/// can include ranges like `a-z`, and is negated by a leading '!'.
+pub fn glob_match(glob: &str, text: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    glob_match_chars(&glob, &text)
+}
+
+fn glob_match_chars(glob: &[char], text: &[char]) -> bool {
+    match glob.first() {
+        None => text.is_empty(),
+        Some('*') => {
+            let (rest, crosses_dirs) = if glob.get(1) == Some(&'*') {
+                (&glob[2 ..], true)
+            } else {
+                (&glob[1 ..], false)
+            };
+            for i in 0 ..= text.len() {
+                if glob_match_chars(rest, &text[i ..]) {
+                    return true;
+                }
+                if i < text.len() && text[i] == '/' && !crosses_dirs {
+                    break;
+                }
+            }
+            false
+        },
+        Some('?') => match text.first() {
+            Some(&c) if c != '/' => glob_match_chars(&glob[1 ..], &text[1 ..]),
+            _ => false,
+        },
+        Some('[') => {
+            let c = match text.first() {
+                Some(&c) => c,
+                None => { return false; },
+            };
+            let end = match glob.iter().skip(2).position(|&g| g == ']') {
+                Some(i) => i + 2,
+                // No closing bracket: treat it as a plain character.
+                None => {
+                    return c == '[' && glob_match_chars(&glob[1 ..], &text[1 ..]);
+                },
+            };
+            let (negated, set) = match glob[1] {
+                '!' => (true, &glob[2 .. end]),
+                _ => (false, &glob[1 .. end]),
+            };
+            let mut found = false;
+            let mut i = 0;
+            while i < set.len() {
+                if i + 2 < set.len() && set[i + 1] == '-' {
+                    found |= set[i] <= c && c <= set[i + 2];
+                    i += 3;
+                } else {
+                    found |= set[i] == c;
+                    i += 1;
+                }
+            }
+            found != negated && glob_match_chars(&glob[end + 1 ..], &text[1 ..])
+        },
+        Some(&g) => text.first() == Some(&g) && glob_match_chars(&glob[1 ..], &text[1 ..]),
+    }
+}
+
+/// Find all the files to convert: the given paths, if they're files, and any files in the
+/// directory trees under the given paths that match the options' globs. Symbolic links found
+/// while searching directories are not followed.
+pub fn find_files(paths: &[PathBuf], options: &BatchOptions) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for path in paths {
+        if fs::metadata(path)?.is_dir() {
+            find_in_dir(path, Path::new(""), options, &mut files)?;
+        } else {
+            files.push(path.clone());
+        }
+    }
+    Ok(files)
+}
+
+fn find_in_dir(root: &Path, relative: &Path, options: &BatchOptions, files: &mut Vec<PathBuf>)
+        -> io::Result<()> {
+    let mut entries = fs::read_dir(root.join(relative))?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let file_type = entry.file_type()?;
+        let relative = relative.join(entry.file_name());
+        if file_type.is_symlink() || !options.matches(&relative, file_type.is_dir()) {
+            debug!("skipping {:?}", relative);
+        } else if file_type.is_dir() {
+            find_in_dir(root, &relative, options, files)?;
+        } else if file_type.is_file() {
+            files.push(root.join(relative));
+        }
+    }
+    Ok(())
+}
+
+/// What happened to a file in a batch conversion.
+#[derive(Debug)]
+pub enum FileOutcome {
+    /// The file was converted, with errors being dealt with according to the error policy this
+    /// many times.
+    Converted { errors: usize },
+
+    /// The conversion halted with an error, and the file was left alone.
+    Skipped(CodeError),
+
+    /// The file couldn't be read, or the converted file couldn't be written.
+    Failed(io::Error),
+}
+
+impl fmt::Display for FileOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            FileOutcome::Converted { errors: 0 } => write!(f, "converted"),
+            FileOutcome::Converted { errors } => write!(f, "converted, with {} errors", errors),
+            FileOutcome::Skipped(e) => write!(f, "skipped: {}", e),
+            FileOutcome::Failed(e) => write!(f, "failed: {}", e),
+        }
+    }
+}
+
+/// Convert one file in place. The output goes to a temporary file next to it, which only replaces
+/// the original once the whole conversion has succeeded, by a single rename. If a backup is asked
+/// for, the original is hard-linked (or, failing that, copied) to it before then, so the original
+/// path always has either the old or the new contents.
+pub fn convert_file(path: &Path, pipeline: &Pipeline, options: &BatchOptions) -> FileOutcome {
+    let file_name = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => {
+            return FileOutcome::Failed(io::Error::new(io::ErrorKind::InvalidInput, "not a file"));
+        },
+    };
+    let temp_path = path.with_file_name(format!(".{}.unicoder-tmp", file_name));
+
+    let errors_before = pipeline.report().handled();
+    match write_converted(path, &temp_path, pipeline) {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => {
+            let _ = fs::remove_file(&temp_path);
+            return FileOutcome::Skipped(e);
+        },
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            return FileOutcome::Failed(e);
+        },
+    }
+    let errors = pipeline.report().handled() - errors_before;
+
+    let replace = || -> io::Result<()> {
+        fs::set_permissions(&temp_path, fs::metadata(path)?.permissions())?;
+        if let Some(ref suffix) = options.backup_suffix {
+            let backup_path = path.with_file_name(format!("{}{}", file_name, suffix));
+            if backup_path.exists() {
+                fs::remove_file(&backup_path)?;
+            }
+            if let Err(e) = fs::hard_link(path, &backup_path) {
+                debug!("can't hard-link {:?} to {:?} ({}); copying it instead", path, backup_path,
+                       e);
+                fs::copy(path, &backup_path)?;
+            }
+        }
+        fs::rename(&temp_path, path)
+    };
+    match replace() {
+        Ok(()) => FileOutcome::Converted { errors },
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            FileOutcome::Failed(e)
+        },
+    }
+}
+
+/// Run a file through the pipeline into the output file. Errors from the pipeline are given
+/// separately from I/O errors writing the output.
+fn write_converted(input_path: &Path, output_path: &Path, pipeline: &Pipeline)
+        -> io::Result<Result<(), CodeError>> {
+    let input = Box::new(ReadChunks::new(File::open(input_path)?));
+    let encoder = pipeline.build(input)
+        .map_err(|msg| io::Error::new(io::ErrorKind::InvalidInput, msg))?;
+    let mut output = File::create(output_path)?;
+    for result in encoder {
+        match result {
+            Ok(chunk) => output.write_all(&chunk)?,
+            Err(e) => { return Ok(Err(e)); },
+        }
+    }
+    output.sync_all()?;
+    Ok(Ok(()))
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("*.txt", "notes.txt"));
+    assert!(!glob_match("*.txt", "notes.txt.bak"));
+    assert!(!glob_match("*.txt", "dir/notes.txt"));
+    assert!(glob_match("**/*.txt", "a/b/notes.txt"));
+    assert!(glob_match("src/**", "src/a/b.c"));
+    assert!(glob_match("file?.[ch]", "file1.c"));
+    assert!(!glob_match("file?.[ch]", "file1.o"));
+    assert!(glob_match("[!a-c]*", "daft"));
+    assert!(!glob_match("[!a-c]*", "bad"));
+}
+
+#[test]
+fn test_convert_tree() {
+    let root = std::env::temp_dir().join(format!("unicoder-test-batch-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("sub/.git")).unwrap();
+    fs::write(root.join("a.txt"), b"caf\xE9\n").unwrap();
+    fs::write(root.join("sub/b.txt"), b"\x80 \x81").unwrap();
+    fs::write(root.join("sub/c.bin"), b"\xE9").unwrap();
+    fs::write(root.join("sub/.git/d.txt"), b"\xE9").unwrap();
+
+    let options = BatchOptions {
+        include: vec!["*.txt".to_owned()],
+        exclude: vec![".git".to_owned()],
+        backup_suffix: Some(".orig".to_owned()),
+    };
+    let files = find_files(std::slice::from_ref(&root), &options).unwrap();
+    assert_eq!(vec![root.join("a.txt"), root.join("sub/b.txt")], files);
+
+    let pipeline = Pipeline::parse(["un_windows1252", "utf8"], ErrorPolicy::Halt).unwrap();
+    let outcome = convert_file(&files[0], &pipeline, &options);
+    assert!(matches!(outcome, FileOutcome::Converted { errors: 0 }), "{}", outcome);
+    assert_eq!("caf\u{E9}\n".as_bytes(), fs::read(&files[0]).unwrap().as_slice());
+    assert_eq!(b"caf\xE9\n", fs::read(root.join("a.txt.orig")).unwrap().as_slice());
+
+    // Errors are counted whatever the policy does with them.
+    fs::write(root.join("a.txt"), b"\x81\x8D").unwrap();
+    let skipping = Pipeline::parse(["un_windows1252", "utf8"], ErrorPolicy::Skip).unwrap();
+    let outcome = convert_file(&files[0], &skipping, &options);
+    assert!(matches!(outcome, FileOutcome::Converted { errors: 2 }), "{}", outcome);
+    assert_eq!(b"", fs::read(&files[0]).unwrap().as_slice());
+    assert_eq!(b"\x81\x8D", fs::read(root.join("a.txt.orig")).unwrap().as_slice());
+
+    // 0x81 isn't defined in Windows-1252.
+    let outcome = convert_file(&files[1], &pipeline, &options);
+    assert!(matches!(outcome, FileOutcome::Skipped(_)), "{}", outcome);
+    assert_eq!(b"\x80 \x81", fs::read(&files[1]).unwrap().as_slice());
+    assert!(!root.join("sub/b.txt.orig").exists());
+    assert!(!root.join("sub/.b.txt.unicoder-tmp").exists());
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -1,58 +1,145 @@
 use std::collections::VecDeque;
 use std::error::Error;
 use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// A position in the input to an encoding.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Position {
+    /// The number of bytes before this position.
+    pub offset: u64,
+
+    /// Line and column numbers, counting from 1, with the column measured in bytes.
+    /// These are only tracked for input which isn't character data.
+    pub line_column: Option<(u64, u64)>,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "byte {}", self.offset)?;
+        match self.line_column {
+            Some((line, column)) => write!(f, " (line {}, column {})", line, column),
+            None => write!(f, " (character {})", self.offset / 4),
+        }
+    }
+}
 
 /// An error in the encoding/decoding process.
 #[derive(Debug)]
 pub struct CodeError {
+    // Boxed, so that the results which carry errors stay small.
+    details: Box<CodeErrorDetails>,
+}
+
+#[derive(Debug)]
+struct CodeErrorDetails {
     message: String,
     bad_bytes: Option<Vec<u8>>,
-    inner: Option<Box<dyn Error>>,
+    inner: Option<Box<dyn Error + Send + Sync>>,
     encoding_name: Option<String>,
+    position: Option<Position>,
+    unmappable: Option<u32>,
 }
 
 impl CodeError {
     /// Creates a new error with the given message.
     pub fn new<S: Into<String>>(message: S) -> CodeError {
         CodeError {
-            message: message.into(),
-            bad_bytes: None,
-            inner: None,
-            encoding_name: None,
+            details: Box::new(CodeErrorDetails {
+                message: message.into(),
+                bad_bytes: None,
+                inner: None,
+                encoding_name: None,
+                position: None,
+                unmappable: None,
+            }),
         }
     }
 
     /// Include the bytes leading up to the error.
     pub fn with_bytes(mut self, bytes: Vec<u8>) -> CodeError {
-        self.bad_bytes = Some(bytes);
+        self.details.bad_bytes = Some(bytes);
+        self
+    }
+
+    /// Say that the error is because the given code point can't be represented by the encoding.
+    pub fn with_unmappable(mut self, codepoint: u32) -> CodeError {
+        self.details.unmappable = Some(codepoint);
         self
     }
 
     /// Include an inner error that caused this one.
-    pub fn with_inner<E: Error + 'static>(mut self, inner: E) -> CodeError {
-        self.inner = Some(Box::new(inner) as Box<dyn Error>);
+    pub fn with_inner<E: Error + Send + Sync + 'static>(mut self, inner: E) -> CodeError {
+        self.details.inner = Some(Box::new(inner) as Box<dyn Error + Send + Sync>);
         self
     }
 
     /// Include an inner error that caused this one.
-    pub fn set_inner(&mut self, inner: Option<Box<dyn Error>>) {
-        self.inner = inner;
+    pub fn set_inner(&mut self, inner: Option<Box<dyn Error + Send + Sync>>) {
+        self.details.inner = inner;
     }
 
+    /// Include the name of the encoding in which the error happened.
     pub fn with_name<T: Into<String>>(mut self, name: T) -> CodeError {
-        self.encoding_name = Some(name.into());
+        self.details.encoding_name = Some(name.into());
         self
     }
+
+    /// Include the position in the input to the encoding where the error happened.
+    pub fn with_position(mut self, position: Position) -> CodeError {
+        self.details.position = Some(position);
+        self
+    }
+
+    /// The bytes leading up to the error, if known.
+    pub fn bad_bytes(&self) -> Option<&[u8]> {
+        self.details.bad_bytes.as_deref()
+    }
+
+    /// The code point which the encoding couldn't represent, if that's what the error is.
+    pub fn unmappable(&self) -> Option<u32> {
+        self.details.unmappable
+    }
+
+    /// The name of the encoding in which the error happened, if known.
+    pub fn encoding_name(&self) -> Option<&str> {
+        self.details.encoding_name.as_deref()
+    }
+
+    /// The position in the input to the encoding where the error happened, if known.
+    pub fn position(&self) -> Option<Position> {
+        self.details.position
+    }
+
+    /// The byte offset in the input to the encoding where the error happened, if known.
+    pub fn offset(&self) -> Option<u64> {
+        self.details.position.map(|position| position.offset)
+    }
+
+    /// The line number (counting from 1) in the input to the encoding where the error happened,
+    /// if known.
+    pub fn line(&self) -> Option<u64> {
+        self.details.position.and_then(|position| position.line_column).map(|(line, _)| line)
+    }
+
+    /// The column number (counting from 1, in bytes) in the input to the encoding where the
+    /// error happened, if known.
+    pub fn column(&self) -> Option<u64> {
+        self.details.position.and_then(|position| position.line_column).map(|(_, column)| column)
+    }
 }
 
 impl fmt::Display for CodeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         write!(f, "Encoding Error")?;
-        if let Some(ref name) = self.encoding_name {
+        if let Some(ref name) = self.details.encoding_name {
             write!(f, " in {}", name)?;
         }
-        write!(f, ": {}", self.message)?;
-        if let Some(ref bytes) = self.bad_bytes {
+        if let Some(ref position) = self.details.position {
+            write!(f, " at {}", position)?;
+        }
+        write!(f, ": {}", self.details.message)?;
+        if let Some(ref bytes) = self.details.bad_bytes {
             if bytes.is_empty() {
                 write!(f, " (input: [])")?;
             } else {
@@ -63,7 +150,7 @@ impl fmt::Display for CodeError {
                 write!(f, "])")?;
             }
         }
-        if let Some(ref e) = self.inner {
+        if let Some(ref e) = self.details.inner {
             write!(f, "\ndue to {}", e)?;
         }
         Ok(())
@@ -76,13 +163,36 @@ impl Error for CodeError {
     }
 
     fn cause(&self) -> Option<&dyn Error> {
-        match self.inner {
-            Some(ref innerbox) => Some(innerbox.as_ref()),
+        match self.details.inner {
+            Some(ref innerbox) => Some(innerbox.as_ref() as &dyn Error),
             None => None,
         }
     }
 }
 
+/// The kinds of data that encodings take as input and produce as output.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StreamKind {
+    /// Any sequence of bytes.
+    Bytes,
+
+    /// Character data: Unicode code points, 4 bytes each (UTF-32BE).
+    Chars,
+
+    /// Human-readable text representing some other data, like hexadecimal or Base64.
+    Text,
+}
+
+impl fmt::Display for StreamKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        f.write_str(match *self {
+            StreamKind::Bytes => "bytes",
+            StreamKind::Chars => "character data (UTF-32BE)",
+            StreamKind::Text => "text",
+        })
+    }
+}
+
 /// Static methods relating to an encoding scheme.
 pub trait EncodingStatics {
     /// Make a new instance of the Encoding, with the given options.
@@ -94,18 +204,45 @@ pub trait EncodingStatics {
     fn print_help();
 }
 
-pub type ByteIterator = Box<dyn Iterator<Item = Result<u8, CodeError>>>;
+/// A source of input data, which yields it in chunks of bytes.
+pub type ChunkIterator = Box<dyn Iterator<Item = Result<Vec<u8>, CodeError>>>;
 
 /// An encoding scheme.
 pub trait Encoding {
     /// Read from an input and produce the next output, or error.
+    ///
+    /// Implementations may consume as much or as little of the input as they like on each call.
+    /// Simple encodings can process everything currently buffered in one go by using
+    /// `EncodingInput::fill_buf` and `EncodingInput::consume`, while stateful ones can work a
+    /// byte or a code point at a time with `get_byte` and `get_bytes`.
     fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>>;
 
+    /// Called exactly once, after `next` has returned None because the input is exhausted, to
+    /// produce any output the encoding has been holding on to, or an error if the input ended in
+    /// the middle of something.
+    fn finish(&mut self) -> Result<Vec<u8>, CodeError> {
+        Ok(vec![])
+    }
+
     /// When an error is encountered, and the error policy is `ErrorPolicy::Replacement`, this is
     /// the data that gets put in the output stream.
     fn replacement(&self) -> Vec<u8> {
         vec![b'?']
     }
+
+    /// Called before output that doesn't come from the encoding itself (like a replacement) is
+    /// put in the output stream, to produce whatever output returns the encoding to its initial
+    /// state, such as an escape sequence shifting back to ASCII.
+    fn reset(&mut self) -> Vec<u8> {
+        vec![]
+    }
+
+    /// Whether the encoding turns some invalid input into characters instead of treating it as an
+    /// error. Those characters can't be told apart from the same ones in valid input, so such an
+    /// encoding can't be used with the `SurrogateEscape` error policy.
+    fn decodes_invalid_input(&self) -> bool {
+        false
+    }
 }
 
 /// An input that can yield single or multiple bytes.
@@ -119,123 +256,550 @@ pub trait EncodingInput {
     /// Put a previously retrieved byte back in the input buffer.
     /// It will be the next one retrieved.
     fn unget_byte(&mut self, byte: u8);
+
+    /// Get all the input which is currently buffered, reading more from the underlying input
+    /// first if nothing is buffered. An empty slice means EOF.
+    /// None of the bytes are consumed until `consume` is called.
+    fn fill_buf(&mut self) -> Result<&[u8], CodeError>;
+
+    /// Mark some number of bytes returned by `fill_buf` or `peek` as consumed.
+    fn consume(&mut self, n: usize);
+
+    /// Look at the next `n` bytes of input, reading as much from the underlying input as it takes,
+    /// without consuming any of them. Fewer than `n` bytes are returned only at EOF.
+    fn peek(&mut self, n: usize) -> Result<&[u8], CodeError>;
 }
 
 // Internal structure for buffering the input to the Encoding. This is kept separate from Encoder
 // so that it can be borrowed mutably while the Encoding is also borrowed mutably.
 struct BufferedInput {
-    pub input: ByteIterator,
-    pub input_buffer: VecDeque<u8>,
+    input: ChunkIterator,
+    buffer: Vec<u8>,
+    pos: usize,
+    ungot: VecDeque<u8>,
+    upstream_error: Option<CodeError>,
+    journal: Option<Vec<u8>>,
+    track_lines: bool,
+    offset: u64,
+    line: u64,
+    column: u64,
+    prev_column: u64,
 }
 
 impl BufferedInput {
-    pub fn new(input: ByteIterator) -> BufferedInput {
+    pub fn new(input: ChunkIterator) -> BufferedInput {
         BufferedInput {
             input,
-            input_buffer: VecDeque::new(),
+            buffer: vec![],
+            pos: 0,
+            ungot: VecDeque::new(),
+            upstream_error: None,
+            journal: None,
+            track_lines: true,
+            offset: 0,
+            line: 0,
+            column: 0,
+            prev_column: 0,
+        }
+    }
+
+    /// The position of the next byte to be consumed.
+    pub fn position(&self) -> Position {
+        Position {
+            offset: self.offset,
+            line_column: if self.track_lines {
+                Some((self.line + 1, self.column + 1))
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Update the position for some bytes having been consumed.
+    fn advance(&mut self, bytes: &[u8]) {
+        self.offset += bytes.len() as u64;
+        if let Some(ref mut journal) = self.journal {
+            journal.extend_from_slice(bytes);
+        }
+        if self.track_lines {
+            for &byte in bytes {
+                if byte == b'\n' {
+                    self.line += 1;
+                    self.prev_column = self.column;
+                    self.column = 0;
+                } else {
+                    self.column += 1;
+                }
+            }
+        }
+    }
+
+    /// Update the position for a byte having been put back.
+    /// Only one line's worth of history is kept, so this is only exact as long as no more than one
+    /// newline at a time gets put back.
+    fn retreat(&mut self, byte: u8) {
+        self.offset -= 1;
+        if let Some(ref mut journal) = self.journal {
+            journal.pop();
+        }
+        if self.track_lines {
+            if byte == b'\n' {
+                self.line -= 1;
+                self.column = self.prev_column;
+            } else {
+                self.column = self.column.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Is there any input available without reading from the underlying input?
+    pub fn has_buffered(&self) -> bool {
+        !self.ungot.is_empty() || self.pos < self.buffer.len()
+    }
+
+    /// Replace the (exhausted) buffer with the next non-empty chunk from the underlying input.
+    fn refill(&mut self) -> Option<Result<(), CodeError>> {
+        match self.next_chunk()? {
+            Ok(chunk) => {
+                self.buffer = chunk;
+                self.pos = 0;
+                Some(Ok(()))
+            },
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    /// Get the next non-empty chunk from the underlying input.
+    fn next_chunk(&mut self) -> Option<Result<Vec<u8>, CodeError>> {
+        loop {
+            match self.input.next() {
+                Some(Ok(chunk)) => {
+                    if !chunk.is_empty() {
+                        return Some(Ok(chunk));
+                    }
+                },
+                Some(Err(e)) => {
+                    // The encoding only gets told that something went wrong; the error itself is
+                    // kept so that the encoder can pass it on as it was.
+                    let msg = e.to_string();
+                    self.upstream_error = Some(e);
+                    return Some(Err(CodeError::new(msg)));
+                },
+                None => { return None; },
+            }
+        }
+    }
+}
+
+/// Splits up chunks of character data so that surrogate escapes (U+DC80..U+DCFF) each come in a
+/// chunk of their own, and the encoding never gets to see them as part of a larger run of input.
+struct SplitEscapes {
+    input: ChunkIterator,
+    pending: VecDeque<Vec<u8>>,
+    carry: Vec<u8>,
+}
+
+impl SplitEscapes {
+    pub fn new(input: ChunkIterator) -> SplitEscapes {
+        SplitEscapes {
+            input,
+            pending: VecDeque::new(),
+            carry: vec![],
         }
     }
 }
 
+impl Iterator for SplitEscapes {
+    type Item = Result<Vec<u8>, CodeError>;
+    fn next(&mut self) -> Option<Result<Vec<u8>, CodeError>> {
+        while self.pending.is_empty() {
+            let mut chunk = match self.input.next() {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(e)) => { return Some(Err(e)); },
+                None if self.carry.is_empty() => { return None; },
+                None => { return Some(Ok(std::mem::take(&mut self.carry))); },
+            };
+            if !self.carry.is_empty() {
+                chunk.splice(0 .. 0, self.carry.drain(..));
+            }
+            // Hold back any partial character for the next chunk.
+            self.carry = chunk.split_off(chunk.len() - chunk.len() % 4);
+            let mut start = 0;
+            for (i, unit) in chunk.chunks_exact(4).enumerate() {
+                if let [0, 0, 0xDC, 0x80 ..= 0xFF] = unit {
+                    if start < i * 4 {
+                        self.pending.push_back(chunk[start .. i * 4].to_vec());
+                    }
+                    self.pending.push_back(unit.to_vec());
+                    start = i * 4 + 4;
+                }
+            }
+            if start == 0 {
+                self.pending.push_back(chunk);
+            } else if start < chunk.len() {
+                self.pending.push_back(chunk[start ..].to_vec());
+            }
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+/// The size at which an Encoder stops accumulating output and hands it on.
+const OUTPUT_CHUNK_SIZE: usize = 64 * 1024;
+
 /// An encoder. Takes an input, and an encoding, and produces output as needed.
-/// The encodings can work on multiple bytes, but Encoder presents itself as a byte-oriented
-/// iterator by buffering the data internally.
+/// The encodings can work on any amount of data at a time; Encoder gathers up their output and
+/// presents itself as an iterator over chunks of bytes.
 pub struct Encoder {
     encoding: Box<dyn Encoding>,
     encoding_name: String,
     input: BufferedInput,
-    output_buffer: VecDeque<u8>,
     stashed_error: Option<CodeError>,
     error_policy: ErrorPolicy,
+    report: ErrorReport,
+    replacement: Option<Vec<u8>>,
+    replacement_text: Option<String>,
+    input_kind: StreamKind,
+    output_kind: StreamKind,
+    finished: bool,
 }
 
 impl Encoder {
-    /// Make a new encoder, using the given byte-oriented iterator as input, and the given
-    /// encoding.
-    pub fn new<T: Into<String>>(input: ByteIterator, encoding: Box<dyn Encoding>, enc_name: T,
+    /// Make a new encoder, using the given chunk iterator as input, and the given encoding.
+    /// The kinds of data it takes and produces are those the registry has for `enc_name` (which
+    /// may have options after a comma), or bytes if it isn't there.
+    pub fn new<T: Into<String>>(input: ChunkIterator, encoding: Box<dyn Encoding>, enc_name: T,
                                 error_policy: ErrorPolicy)
             -> Encoder {
-        Encoder {
+        let mut input = BufferedInput::new(input);
+        if error_policy == ErrorPolicy::BackslashReplace
+                || error_policy == ErrorPolicy::SurrogateEscape {
+            // Keep track of what each step of the encoding consumed, so that it can be escaped.
+            input.journal = Some(vec![]);
+        }
+        let encoding_name = enc_name.into();
+        let name = encoding_name.split(',').next().unwrap();
+        let (input_kind, output_kind) = crate::encodings::get_stream_kinds(name)
+            .unwrap_or((StreamKind::Bytes, StreamKind::Bytes));
+        let encoder = Encoder {
             encoding,
-            encoding_name: enc_name.into(),
-            input: BufferedInput::new(input),
-            output_buffer: VecDeque::new(),
+            encoding_name,
+            input,
             stashed_error: None,
             error_policy,
+            report: ErrorReport::new(),
+            replacement: None,
+            replacement_text: None,
+            input_kind: StreamKind::Bytes,
+            output_kind: StreamKind::Bytes,
+            finished: false,
+        };
+        encoder.with_input_kind(input_kind).with_output_kind(output_kind)
+    }
+
+    /// Use the given report to record errors in, under the `Report` error policy, instead of the
+    /// encoder's own.
+    pub fn with_report(mut self, report: ErrorReport) -> Encoder {
+        self.report = report;
+        self
+    }
+
+    /// Use the given output in place of errors under the `Replace` and `Report` error policies,
+    /// instead of the encoding's own replacement.
+    pub fn with_replacement(mut self, replacement: Vec<u8>) -> Encoder {
+        self.replacement = Some(replacement);
+        self
+    }
+
+    /// Use the given text in place of errors under the `Replace` and `Report` error policies,
+    /// encoded by the encoding itself, which must take character data. Unlike output given to
+    /// `with_replacement`, this carries on from whatever state the encoding is in.
+    pub fn with_replacement_text(mut self, text: String) -> Encoder {
+        self.replacement_text = Some(text);
+        self
+    }
+
+    fn replacement(&mut self) -> Vec<u8> {
+        if let Some(text) = self.replacement_text.clone() {
+            match self.encode_text(&text) {
+                Ok(output) => { return output; },
+                Err(e) => { warn!("{} can't encode {:?}: {}", self.encoding_name, text, e); },
+            }
+        }
+        let mut output = self.encoding.reset();
+        match self.replacement {
+            Some(ref replacement) => output.extend_from_slice(replacement),
+            None => output.extend(self.encoding.replacement()),
         }
+        output
     }
-}
 
-impl Iterator for Encoder {
-    type Item = Result<u8, CodeError>;
-    fn next(&mut self) -> Option<Result<u8, CodeError>> {
-        if self.output_buffer.is_empty() {
-            loop {
-                match self.encoding.next(&mut self.input as &mut dyn EncodingInput) {
-                    Some(Ok(bytes)) => {
-                        self.output_buffer.extend(bytes);
-                    },
-                    Some(Err(e)) => {
-                        debug!("{} returned error: {}", self.encoding_name, e);
-                        match self.error_policy {
-                            ErrorPolicy::Halt => {
-                                // TODO: what if stashed_error is Some already?
-                                self.stashed_error = Some(e.with_name(self.encoding_name.as_str()));
-                            },
-                            ErrorPolicy::Skip => {
-                                continue;
-                            },
-                            ErrorPolicy::Replace => {
-                                let replacement = self.encoding.replacement();
-                                self.output_buffer.extend(replacement);
-                            }
-                        }
+    /// The report which errors are recorded in under the `Report` error policy.
+    pub fn report(&self) -> &ErrorReport {
+        &self.report
+    }
+
+    /// Say what kind of data the input is. This determines how positions in it are reported:
+    /// line and column numbers are tracked for anything but character data.
+    pub fn with_input_kind(mut self, kind: StreamKind) -> Encoder {
+        let was_chars = self.input_kind == StreamKind::Chars;
+        self.input_kind = kind;
+        self.input.track_lines = kind != StreamKind::Chars;
+        if kind == StreamKind::Chars && !was_chars && self.error_policy == ErrorPolicy::SurrogateEscape {
+            let input = std::mem::replace(&mut self.input.input, Box::new(std::iter::empty()));
+            self.input.input = Box::new(SplitEscapes::new(input));
+        }
+        self
+    }
+
+    /// Under the `SurrogateEscape` policy, if the encoding turns character data into bytes and
+    /// the next character of the input is an escaped byte, take it and return the byte.
+    fn unescape_byte(&mut self) -> Option<u8> {
+        if self.error_policy != ErrorPolicy::SurrogateEscape
+                || self.input_kind != StreamKind::Chars
+                || self.output_kind != StreamKind::Bytes {
+            return None;
+        }
+        let byte = match self.input.fill_buf() {
+            Ok(&[0, 0, 0xDC, byte @ 0x80 ..= 0xFF, ..]) => byte,
+            _ => { return None; },
+        };
+        self.input.consume(4);
+        Some(byte)
+    }
+
+    /// Say what kind of data the encoding produces. This determines how the escapes from the
+    /// escaping error policies are output.
+    pub fn with_output_kind(mut self, kind: StreamKind) -> Encoder {
+        self.output_kind = kind;
+        self
+    }
+
+    /// Output some (ASCII) text in place of an error: as character data, as it is, or encoded by
+    /// the encoding itself if it takes character data.
+    fn text_output(&mut self, text: &str) -> Vec<u8> {
+        let chars = text.chars().flat_map(|c| (c as u32).to_be_bytes()).collect::<Vec<u8>>();
+        if self.output_kind == StreamKind::Chars {
+            return chars;
+        } else if self.input_kind != StreamKind::Chars {
+            return text.as_bytes().to_vec();
+        }
+        match self.encode_text(text) {
+            Ok(output) => output,
+            Err(e) => {
+                warn!("{} can't encode {:?}: {}", self.encoding_name, text, e);
+                self.replacement()
+            },
+        }
+    }
+
+    /// Encode some text with the encoding itself, which takes character data, carrying on from
+    /// the state it's in.
+    fn encode_text(&mut self, text: &str) -> Result<Vec<u8>, CodeError> {
+        let chars = text.chars().flat_map(|c| (c as u32).to_be_bytes()).collect::<Vec<u8>>();
+        let mut input = BufferedInput::new(Box::new(Some(Ok(chars)).into_iter()));
+        let mut output = vec![];
+        while let Some(result) = self.encoding.next(&mut input) {
+            output.extend(result?);
+        }
+        Ok(output)
+    }
+
+    /// Deal with an error from the encoding according to the error policy: either add something
+    /// to the output in its place, or give it back if processing should stop.
+    /// `position` is where the input was when the encoding started on the erroneous data.
+    fn handle_error(&mut self, mut e: CodeError, position: Position, output: &mut Vec<u8>)
+            -> Result<(), CodeError> {
+        debug!("{} returned error: {}", self.encoding_name, e);
+        // Errors passed along from earlier encodings already say where they came from.
+        if e.encoding_name().is_none() {
+            e = e.with_name(self.encoding_name.as_str()).with_position(position);
+        }
+        match self.error_policy {
+            ErrorPolicy::Halt => {
+                return Err(e);
+            },
+            ErrorPolicy::Skip => (),
+            ErrorPolicy::Replace => {
+                output.extend(self.replacement());
+            },
+            ErrorPolicy::Report => {
+                output.extend(self.replacement());
+                self.report.record(e);
+            },
+            ErrorPolicy::XmlCharRefReplace
+                    | ErrorPolicy::BackslashReplace
+                    | ErrorPolicy::NameReplace => {
+                let text = match e.unmappable() {
+                    Some(codepoint) => Some(self.error_policy.escape(codepoint)),
+                    // Invalid input has no character to refer to, but its bytes can be escaped.
+                    None if self.error_policy == ErrorPolicy::BackslashReplace => {
+                        let journal = self.input.journal.as_deref().unwrap_or(&[]);
+                        let bytes = if journal.is_empty() {
+                            e.bad_bytes().unwrap_or(&[])
+                        } else {
+                            journal
+                        };
+                        Some(bytes.iter().map(|byte| format!("\\x{:02x}", byte)).collect())
                     },
-                    None => {
-                        debug!("{} returned EOF", self.encoding_name);
+                    None => None,
+                };
+                match text {
+                    Some(text) => {
+                        let escaped = self.text_output(&text);
+                        output.extend(escaped);
                     },
+                    None => output.extend(self.replacement()),
                 }
-                break;
-            }
-        }
-
-        if let Some(byte) = self.output_buffer.pop_front() {
-            return Some(Ok(byte));
+            },
+            ErrorPolicy::SurrogateEscape => {
+                // Only invalid input can be escaped, and only bytes that aren't ASCII, as they'd
+                // otherwise be confused with the ASCII characters; anything else halts.
+                let journal = self.input.journal.as_deref().unwrap_or(&[]);
+                if self.input_kind == StreamKind::Chars || self.output_kind != StreamKind::Chars
+                        || journal.iter().any(|&byte| byte < 0x80) {
+                    return Err(e);
+                }
+                for &byte in journal {
+                    output.extend_from_slice(&[0, 0, 0xDC, byte]);
+                }
+            },
         }
+        self.report.count_handled();
+        Ok(())
+    }
+}
 
+impl Iterator for Encoder {
+    type Item = Result<Vec<u8>, CodeError>;
+    fn next(&mut self) -> Option<Result<Vec<u8>, CodeError>> {
         if let Some(err) = self.stashed_error.take() {
             debug!("{} returning stashed error", self.encoding_name);
             return Some(Err(err));
         }
 
-        None
+        // Keep running the encoding until it has produced something, and then for as long as it
+        // can continue without having to wait on more input.
+        let mut output = vec![];
+        loop {
+            let mut eof = false;
+            let position = self.input.position();
+            if let Some(ref mut journal) = self.input.journal {
+                journal.clear();
+            }
+            if let Some(byte) = self.unescape_byte() {
+                output.extend(self.encoding.reset());
+                output.push(byte);
+                continue;
+            }
+            let result = match self.encoding.next(&mut self.input as &mut dyn EncodingInput) {
+                Some(result) => result,
+                None => {
+                    if self.finished {
+                        break;
+                    }
+                    debug!("{} returned EOF; finishing", self.encoding_name);
+                    eof = true;
+                    self.finished = true;
+                    self.encoding.finish()
+                },
+            };
+
+            match result {
+                Ok(bytes) => {
+                    if output.is_empty() {
+                        output = bytes;
+                    } else {
+                        output.extend_from_slice(&bytes);
+                    }
+                },
+                Err(e) => {
+                    // Errors from earlier encodings have already been through their own error
+                    // policy, which said to halt, so they go straight through.
+                    let result = match self.input.upstream_error.take() {
+                        Some(upstream) => Err(upstream),
+                        None => self.handle_error(e, position, &mut output),
+                    };
+                    if let Err(e) = result {
+                        if output.is_empty() {
+                            return Some(Err(e));
+                        }
+                        // Hand over the output so far, and return the error next time.
+                        self.stashed_error = Some(e);
+                        break;
+                    }
+                },
+            }
+
+            if eof || (!output.is_empty()
+                    && (output.len() >= OUTPUT_CHUNK_SIZE || !self.input.has_buffered())) {
+                break;
+            }
+        }
+
+        if output.is_empty() {
+            None
+        } else {
+            Some(Ok(output))
+        }
+    }
+}
+
+/// An encoding which passes its input through unchanged.
+pub struct IdentityEncoding;
+
+impl Encoding for IdentityEncoding {
+    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
+        let bytes = match input.fill_buf() {
+            Ok([]) => { return None; },
+            Ok(buf) => buf.to_vec(),
+            Err(e) => { return Some(Err(e)); },
+        };
+        input.consume(bytes.len());
+        Some(Ok(bytes))
     }
 }
 
 impl EncodingInput for BufferedInput {
     fn get_byte(&mut self) -> Option<Result<u8, CodeError>> {
-        if let Some(byte) = self.input_buffer.pop_front() {
-            Some(Ok(byte))
+        let byte = if let Some(byte) = self.ungot.pop_front() {
+            byte
         } else {
-            self.input.next()
-        }
+            if self.pos == self.buffer.len() {
+                match self.refill() {
+                    Some(Ok(())) => (),
+                    Some(Err(e)) => { return Some(Err(e)); },
+                    None => { return None; },
+                }
+            }
+            self.pos += 1;
+            self.buffer[self.pos - 1]
+        };
+        self.advance(&[byte]);
+        Some(Ok(byte))
     }
 
     fn get_bytes(&mut self, n: usize) -> Option<Result<Vec<u8>, CodeError>> {
         let mut result: Vec<u8> = Vec::with_capacity(n);
         while result.len() < n {
-            if let Some(byte) = self.input_buffer.pop_front() {
+            if let Some(byte) = self.ungot.pop_front() {
                 result.push(byte);
+            } else if self.pos < self.buffer.len() {
+                let take = (n - result.len()).min(self.buffer.len() - self.pos);
+                result.extend_from_slice(&self.buffer[self.pos .. self.pos + take]);
+                self.pos += take;
             } else {
-                match self.input.next() {
-                    Some(Ok(byte)) => { result.push(byte); },
+                match self.refill() {
+                    Some(Ok(())) => (),
                     Some(Err(e)) => {
                         error!("Error in adapter read: {}", e);
+                        self.advance(&result);
                         return Some(Err(CodeError::new(format!("error getting {} bytes", n))
                                                   .with_bytes(result)
                                                   .with_inner(e)));
                     },
                     None => {
+                        self.advance(&result);
                         if result.is_empty() {
                             return None;
                         } else {
@@ -248,16 +812,71 @@ impl EncodingInput for BufferedInput {
                 }
             }
         }
+        self.advance(&result);
         Some(Ok(result))
     }
 
     fn unget_byte(&mut self, byte: u8) {
-        self.input_buffer.push_back(byte);
+        self.retreat(byte);
+        self.ungot.push_back(byte);
+    }
+
+    fn fill_buf(&mut self) -> Result<&[u8], CodeError> {
+        if !self.ungot.is_empty() {
+            return Ok(self.ungot.make_contiguous());
+        }
+        if self.pos == self.buffer.len() {
+            match self.refill() {
+                Some(Ok(())) => (),
+                Some(Err(e)) => { return Err(e); },
+                None => { return Ok(&[]); },
+            }
+        }
+        Ok(&self.buffer[self.pos ..])
+    }
+
+    fn peek(&mut self, n: usize) -> Result<&[u8], CodeError> {
+        if !self.ungot.is_empty() {
+            // Put the bytes that were put back in front of the buffer, so it's all contiguous.
+            let mut buffer: Vec<u8> = self.ungot.drain(..).collect();
+            buffer.extend_from_slice(&self.buffer[self.pos ..]);
+            self.buffer = buffer;
+            self.pos = 0;
+        }
+        while self.buffer.len() - self.pos < n {
+            match self.next_chunk() {
+                Some(Ok(chunk)) => {
+                    self.buffer.drain(.. self.pos);
+                    self.pos = 0;
+                    self.buffer.extend_from_slice(&chunk);
+                },
+                Some(Err(e)) => { return Err(e); },
+                None => { break; },
+            }
+        }
+        let end = self.buffer.len().min(self.pos + n);
+        Ok(&self.buffer[self.pos .. end])
+    }
+
+    fn consume(&mut self, n: usize) {
+        if !self.ungot.is_empty() {
+            assert!(n <= self.ungot.len(), "consumed more than was buffered");
+            let consumed: Vec<u8> = self.ungot.drain(.. n).collect();
+            self.advance(&consumed);
+        } else {
+            assert!(n <= self.buffer.len() - self.pos, "consumed more than was buffered");
+            let start = self.pos;
+            self.pos += n;
+            // Borrow the buffer separately from the position fields.
+            let buffer = std::mem::take(&mut self.buffer);
+            self.advance(&buffer[start .. start + n]);
+            self.buffer = buffer;
+        }
     }
 }
 
 /// What to do when an error is encountered?
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ErrorPolicy {
     /// Return an error.
     Halt,
@@ -267,4 +886,190 @@ pub enum ErrorPolicy {
 
     /// Return appropriate output data that indicates an error (e.g. U+FFFD or '?'), and continue.
     Replace,
+
+    /// Like `Replace`, but also record every error in the encoder's `ErrorReport`.
+    Report,
+
+    /// Replace characters that can't be encoded with an XML numeric character reference, like
+    /// `&#x1F600;`. Other errors are replaced as with `Replace`.
+    XmlCharRefReplace,
+
+    /// Replace characters that can't be encoded with a backslash escape, like `\U0001f600`, and
+    /// invalid input with escapes of its bytes, like `\xff`.
+    BackslashReplace,
+
+    /// Replace characters that can't be encoded with their name, like `\N{GRINNING FACE}`, or a
+    /// backslash escape if they don't have one. Other errors are replaced as with `Replace`.
+    NameReplace,
+
+    /// Decode each byte of invalid input to a lone surrogate code point, U+DC00 plus the byte
+    /// (U+DC80..U+DCFF, as only bytes that aren't ASCII can be escaped), and encode those code
+    /// points back to the bytes they came from, so that such input survives a decode and
+    /// re-encode unchanged. Other errors, including invalid input with ASCII bytes in it, halt.
+    SurrogateEscape,
+}
+
+impl ErrorPolicy {
+    /// Parse an error policy from its name, as given on the command line.
+    pub fn parse(s: &str) -> Option<ErrorPolicy> {
+        match s {
+            "halt" => Some(ErrorPolicy::Halt),
+            "skip" => Some(ErrorPolicy::Skip),
+            "replace" => Some(ErrorPolicy::Replace),
+            "report" => Some(ErrorPolicy::Report),
+            "xmlcharrefreplace" => Some(ErrorPolicy::XmlCharRefReplace),
+            "backslashreplace" => Some(ErrorPolicy::BackslashReplace),
+            "namereplace" => Some(ErrorPolicy::NameReplace),
+            "surrogateescape" => Some(ErrorPolicy::SurrogateEscape),
+            _ => None,
+        }
+    }
+
+    /// The text which the escaping policies put in place of a character that can't be encoded.
+    pub fn escape(&self, codepoint: u32) -> String {
+        let name = match self {
+            ErrorPolicy::XmlCharRefReplace => { return format!("&#x{:X};", codepoint); },
+            ErrorPolicy::NameReplace => std::char::from_u32(codepoint).and_then(unicode_names::name),
+            _ => None,
+        };
+        match name {
+            Some(name) => format!("\\N{{{}}}", name),
+            None if codepoint <= 0xFF => format!("\\x{:02x}", codepoint),
+            None if codepoint <= 0xFFFF => format!("\\u{:04x}", codepoint),
+            None => format!("\\U{:08x}", codepoint),
+        }
+    }
+}
+
+/// A collection of the errors encountered under the `Report` error policy, and a count of the
+/// errors dealt with under any policy but `Halt`.
+///
+/// Clones share the same collection, so one report can be given to every encoder in a pipeline
+/// (including ones running on other threads) and then inspected once they're done.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorReport {
+    inner: Arc<Mutex<ReportInner>>,
+}
+
+#[derive(Debug, Default)]
+struct ReportInner {
+    /// Each error, along with the name of the input it was found in, if known.
+    errors: Vec<(Option<String>, CodeError)>,
+    input_name: Option<String>,
+    handled: usize,
+}
+
+impl ErrorReport {
+    pub fn new() -> ErrorReport {
+        ErrorReport::default()
+    }
+
+    /// Say which input the errors recorded from now on are found in, when a pipeline is used for
+    /// more than one.
+    pub fn set_input_name<T: Into<String>>(&self, name: T) {
+        self.inner.lock().unwrap().input_name = Some(name.into());
+    }
+
+    /// Add an error to the report.
+    pub fn record(&self, error: CodeError) {
+        let mut inner = self.inner.lock().unwrap();
+        let input_name = inner.input_name.clone();
+        inner.errors.push((input_name, error));
+    }
+
+    /// Count an error as having been dealt with by an encoder's error policy, whether or not it
+    /// is recorded.
+    pub fn count_handled(&self) {
+        self.inner.lock().unwrap().handled += 1;
+    }
+
+    /// How many errors have been dealt with by the encoders' error policies, instead of halting.
+    pub fn handled(&self) -> usize {
+        self.inner.lock().unwrap().handled
+    }
+
+    /// How many errors have been recorded.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().errors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove and return all the errors recorded so far.
+    pub fn take(&self) -> Vec<CodeError> {
+        std::mem::take(&mut self.inner.lock().unwrap().errors)
+            .into_iter()
+            .map(|(_, error)| error)
+            .collect()
+    }
+}
+
+/// The summary of the report: every error in the order they happened, followed by a count of
+/// errors for each encoding.
+impl fmt::Display for ErrorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let inner = self.inner.lock().unwrap();
+        let errors = &inner.errors;
+        if errors.is_empty() {
+            return writeln!(f, "no errors");
+        }
+        writeln!(f, "{} error{}:", errors.len(), if errors.len() == 1 { "" } else { "s" })?;
+        let mut counts: Vec<(&str, usize)> = vec![];
+        for (input_name, error) in errors.iter() {
+            match input_name {
+                Some(input_name) => writeln!(f, "  {}: {}", input_name, error)?,
+                None => writeln!(f, "  {}", error)?,
+            }
+            let name = error.encoding_name().unwrap_or("(unknown)");
+            match counts.iter_mut().find(|(counted, _)| *counted == name) {
+                Some((_, count)) => { *count += 1; },
+                None => { counts.push((name, 1)); },
+            }
+        }
+        writeln!(f, "errors by encoding:")?;
+        for (name, count) in counts {
+            writeln!(f, "  {}: {}", name, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_encoder_chunks() {
+    use crate::encodings::Utf8Decode;
+
+    // A multi-byte sequence split across input chunks, with an error in the middle.
+    let chunks = vec![Ok(b"a\xC3".to_vec()), Ok(vec![]), Ok(b"\xA9\xFFb".to_vec())];
+    let encoder = Encoder::new(Box::new(chunks.into_iter()), Utf8Decode::new("").unwrap(), "un_utf8",
+                               ErrorPolicy::Halt);
+    let results: Vec<_> = encoder.collect();
+    assert_eq!(3, results.len());
+    assert_eq!(&[0, 0, 0, b'a', 0, 0, 0, 0xE9], results[0].as_ref().unwrap().as_slice());
+    assert!(results[1].is_err());
+    assert_eq!(&[0, 0, 0, b'b'], results[2].as_ref().unwrap().as_slice());
+}
+
+#[test]
+fn test_error_position() {
+    use crate::encodings::Utf8Decode;
+
+    let chunks = vec![Ok(b"ab\ncd\n\xC3\xA9f\xFF".to_vec())];
+    let encoder = Encoder::new(Box::new(chunks.into_iter()), Utf8Decode::new("").unwrap(), "un_utf8",
+                               ErrorPolicy::Halt);
+    let e = encoder.filter_map(Result::err).next().unwrap();
+    assert_eq!(Some("un_utf8"), e.encoding_name());
+    assert_eq!(Some(9), e.offset());
+    assert_eq!(Some(3), e.line());
+    assert_eq!(Some(4), e.column());
+    assert!(e.to_string().contains("at byte 9 (line 3, column 4)"));
+
+    // Character data, going by what the registry has for the encoding, has no lines.
+    let chunks = vec![Ok(vec![0, 0, 0, b'\n', 0x80, 0, 0, 0])];
+    let encoder = Encoder::new(Box::new(chunks.into_iter()), Box::new(crate::encodings::Utf8Encode),
+                               "utf8", ErrorPolicy::Halt);
+    let e = encoder.filter_map(Result::err).next().unwrap();
+    assert_eq!(Some(4), e.offset());
+    assert_eq!(None, e.line());
 }
@@ -0,0 +1,374 @@
+use std::fmt;
+
+use super::super::encoding::*;
+use super::get_encoding;
+
+/// How much of the input to look at when guessing its encoding.
+const SAMPLE_SIZE: usize = 64 * 1024;
+
+/// The decoders which are tried when the input has no BOM and isn't UTF-8 or UTF-16, in order of
+/// preference when they're equally plausible.
+const CANDIDATES: [(&str, &str); 6] = [
+    ("un_windows1252", ""),
+    ("un_iso8859", "2"),
+    ("un_iso8859", "5"),
+    ("un_iso8859", "6"),
+    ("un_shift_jis", ""),
+    ("un_cp437", ""),
+];
+
+/// A guess at the encoding of some input.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Detection {
+    /// The name of the decoder to use.
+    pub decoder: &'static str,
+
+    /// The options to give the decoder.
+    pub options: &'static str,
+
+    /// The length of the byte order mark at the start of the input, which should be skipped.
+    pub bom_length: usize,
+
+    /// How sure the guess is, from 0 to 1.
+    pub confidence: f64,
+}
+
+impl Detection {
+    fn new(decoder: &'static str, options: &'static str, bom_length: usize, confidence: f64)
+            -> Detection {
+        Detection { decoder, options, bom_length, confidence }
+    }
+}
+
+/// Formats the detection as the pipeline stage that decodes the input, and the confidence.
+impl fmt::Display for Detection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.decoder)?;
+        if !self.options.is_empty() {
+            write!(f, ",{}", self.options)?;
+        }
+        write!(f, " ({:.0}% confidence)", self.confidence * 100.)
+    }
+}
+
+/// Guess the encoding of some input from a sample of its start. `eof` says whether the sample is
+/// the whole input, or might have been cut off in the middle of a character.
+pub fn detect(sample: &[u8], eof: bool) -> Detection {
+    // Byte order marks. The UTF-32 ones have to be checked before the UTF-16 ones they start
+    // with.
+    if sample.starts_with(&[0xEF, 0xBB, 0xBF]) {
+        return Detection::new("un_utf8", "", 3, 1.);
+    } else if sample.starts_with(&[0, 0, 0xFE, 0xFF]) {
+        return Detection::new("un_utf32", "be", 4, 1.);
+    } else if sample.starts_with(&[0xFF, 0xFE, 0, 0]) {
+        return Detection::new("un_utf32", "le", 4, 1.);
+    } else if sample.starts_with(&[0xFE, 0xFF]) {
+        return Detection::new("un_utf16", "be", 2, 1.);
+    } else if sample.starts_with(&[0xFF, 0xFE]) {
+        return Detection::new("un_utf16", "le", 2, 1.);
+    }
+
+    // UTF-16 without a BOM shows up as lots of zero bytes in every other position. This has to be
+    // checked first, because zero bytes are valid UTF-8.
+    let zeros = |parity| sample.iter().skip(parity).step_by(2).filter(|&&b| b == 0).count();
+    let (even_zeros, odd_zeros) = (zeros(0), zeros(1));
+    let threshold = (sample.len() / 6).max(2);
+    if even_zeros.max(odd_zeros) >= threshold {
+        if odd_zeros == 0 {
+            return Detection::new("un_utf16", "be", 0, 0.8);
+        } else if even_zeros == 0 {
+            return Detection::new("un_utf16", "le", 0, 0.8);
+        }
+    }
+
+    // Text in other encodings is very unlikely to happen to be valid UTF-8, unless it's ASCII, in
+    // which case UTF-8 is right anyway.
+    let valid_utf8 = match std::str::from_utf8(sample) {
+        Ok(_) => true,
+        // Only a character cut off at the end of the sample.
+        Err(e) => !eof && e.error_len().is_none(),
+    };
+    if valid_utf8 {
+        let confidence = if sample.is_ascii() { 1. } else { 0.99 };
+        return Detection::new("un_utf8", "", 0, confidence);
+    }
+
+    // Otherwise, decode the sample with each of the candidates, and see which gives the most
+    // plausible text. The wrong ones will log errors about the input that aren't really errors,
+    // so logging is turned off while they run.
+    let quiet = QuietLogging::new();
+    let mut scores: Vec<(usize, f64)> = CANDIDATES.iter()
+        .enumerate()
+        .map(|(i, &(name, options))| (i, score_candidate(name, options, sample, eof)))
+        .collect();
+    drop(quiet);
+    // Stable, so ties go to the earlier candidate.
+    scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    for &(i, score) in &scores {
+        debug!("{:?} scores {}", CANDIDATES[i], score);
+    }
+
+    let (best, best_score) = scores[0];
+    let runner_up_score = scores[1].1;
+    let confidence = if best_score <= 0. {
+        0.
+    } else if runner_up_score <= 0. {
+        0.95
+    } else {
+        (best_score / (best_score + runner_up_score)).min(0.95)
+    };
+    Detection::new(CANDIDATES[best].0, CANDIDATES[best].1, 0, confidence)
+}
+
+/// Turns logging off until it's dropped, and then sets it back to the level it was at.
+struct QuietLogging {
+    level: log::LevelFilter,
+}
+
+impl QuietLogging {
+    fn new() -> QuietLogging {
+        let level = log::max_level();
+        log::set_max_level(log::LevelFilter::Off);
+        QuietLogging { level }
+    }
+}
+
+impl Drop for QuietLogging {
+    fn drop(&mut self) {
+        log::set_max_level(self.level);
+    }
+}
+
+/// Decode the sample with a candidate decoder, and score how plausible the result is as text.
+fn score_candidate(name: &str, options: &str, sample: &[u8], eof: bool) -> f64 {
+    let decoder = match get_encoding(name, options) {
+        Ok(decoder) => decoder,
+        Err(_) => { return f64::MIN; },
+    };
+    let input = Box::new(Some(Ok(sample.to_vec())).into_iter());
+    let encoder = Encoder::new(input, decoder, name, ErrorPolicy::Report);
+    let report = encoder.report().clone();
+    let decoded: Vec<u8> = encoder.flat_map(|result| result.unwrap_or_default()).collect();
+
+    let mut score = 0.;
+    for error in report.take() {
+        // A multi-byte character cut off at the end of the sample is not the decoder's fault.
+        let truncated = !eof && error.offset().unwrap_or(0) + 4 > sample.len() as u64;
+        if !truncated {
+            score -= 20.;
+        }
+    }
+
+    let mut previous = CharClass::Other;
+    for bytes in decoded.chunks_exact(4) {
+        let class = CharClass::of(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+        score += class.score_after(previous);
+        previous = class;
+    }
+    score
+}
+
+/// Kinds of characters, for judging how plausible some decoded text is.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum CharClass {
+    /// ASCII letters.
+    AsciiLetter,
+
+    /// Other ASCII characters.
+    Ascii,
+
+    /// Non-ASCII letters in the Latin script.
+    Latin,
+
+    /// Other alphabetic characters, like Greek, Cyrillic or Arabic letters.
+    OtherLetter,
+
+    /// Kana and CJK ideographs.
+    Cjk,
+
+    /// Half-width katakana.
+    HalfwidthKana,
+
+    /// Punctuation and symbols which are common in text, like curly quotes and dashes.
+    CommonSymbol,
+
+    /// Control characters (apart from whitespace), and the replacement character.
+    Control,
+
+    /// Anything else: box drawing characters, rarer symbols, etc.
+    Other,
+}
+
+impl CharClass {
+    fn of(codepoint: u32) -> CharClass {
+        let c = match std::char::from_u32(codepoint) {
+            Some(c) => c,
+            None => { return CharClass::Control; },
+        };
+        match codepoint {
+            0x09 | 0x0A | 0x0D => CharClass::Ascii,
+            0x00 ..= 0x1F | 0x7F ..= 0x9F | 0xFFFD => CharClass::Control,
+            _ if c.is_ascii_alphabetic() => CharClass::AsciiLetter,
+            _ if c.is_ascii() => CharClass::Ascii,
+            0xA0 | 0xA9 | 0xAB | 0xB0 | 0xB7 | 0xBB | 0x2013 | 0x2014 | 0x2018 | 0x2019 | 0x201C
+                | 0x201D | 0x2022 | 0x2026 | 0x20AC | 0x3000 ..= 0x3002 => CharClass::CommonSymbol,
+            0x3040 ..= 0x30FF | 0x4E00 ..= 0x9FFF | 0xFF01 ..= 0xFF5E => CharClass::Cjk,
+            0xFF61 ..= 0xFF9F => CharClass::HalfwidthKana,
+            0xC0 ..= 0x24F if c.is_alphabetic() => CharClass::Latin,
+            _ if c.is_alphabetic() => CharClass::OtherLetter,
+            _ => CharClass::Other,
+        }
+    }
+
+    /// How much a character of this class, following one of the given class, counts for or
+    /// against the text being right. ASCII counts for nothing, because it decodes the same way in
+    /// all the candidates.
+    fn score_after(self, previous: CharClass) -> f64 {
+        let in_word = previous == CharClass::AsciiLetter || previous == CharClass::Latin;
+        match self {
+            CharClass::AsciiLetter | CharClass::Ascii => 0.,
+            // Accented letters mostly come among unaccented ones; long runs of them are more
+            // likely to be some other script decoded wrongly.
+            CharClass::Latin if previous == CharClass::AsciiLetter => 2.,
+            CharClass::Latin => 0.5,
+            CharClass::OtherLetter if previous == CharClass::OtherLetter => 2.,
+            CharClass::OtherLetter => 0.5,
+            // Ideographs right next to Latin letters are what Latin text looks like when decoded
+            // as a multi-byte encoding.
+            CharClass::Cjk if in_word => -1.,
+            CharClass::Cjk => 2.,
+            CharClass::HalfwidthKana if previous == CharClass::HalfwidthKana => 0.5,
+            CharClass::HalfwidthKana => -1.,
+            CharClass::CommonSymbol => 0.5,
+            CharClass::Control => -10.,
+            CharClass::Other => -1.,
+        }
+    }
+}
+
+/// Make the decoder for a detection.
+fn detected_decoder(detection: &Detection) -> Box<dyn Encoding> {
+    get_encoding(detection.decoder, detection.options)
+        .expect("detected an encoding that can't be set up")
+}
+
+pub struct AutoDecode {
+    inner: Option<Box<dyn Encoding>>,
+}
+
+impl EncodingStatics for AutoDecode {
+    fn new(options: &str) -> Result<Box<dyn Encoding>, String> {
+        if !options.is_empty() {
+            return Err("invalid options".into());
+        }
+        Ok(Box::new(AutoDecode { inner: None }))
+    }
+
+    fn print_help() {
+        println!("Guesses the encoding of the input, and decodes it into character data (UTF-32BE).");
+        println!("Recognizes byte order marks for UTF-8, UTF-16 and UTF-32, and otherwise tries UTF-8,");
+        println!("UTF-16, Windows 1252, ISO 8859-2, -5 and -6, Shift JIS and CP437, based on the");
+        println!("first 64 KiB of input. The guess is logged in verbose mode; use 'detect' to only");
+        println!("print it.");
+        println!("(no options)");
+    }
+}
+
+impl Encoding for AutoDecode {
+    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
+        if self.inner.is_none() {
+            let detection = match input.peek(SAMPLE_SIZE) {
+                Ok([]) => { return None; },
+                Ok(sample) => detect(sample, sample.len() < SAMPLE_SIZE),
+                Err(e) => { return Some(Err(e)); },
+            };
+            info!("detected encoding: {}", detection);
+            input.consume(detection.bom_length);
+            self.inner = Some(detected_decoder(&detection));
+        }
+        self.inner.as_mut().unwrap().next(input)
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, CodeError> {
+        match self.inner {
+            Some(ref mut inner) => inner.finish(),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn replacement(&self) -> Vec<u8> {
+        match self.inner {
+            Some(ref inner) => inner.replacement(),
+            None => vec![0, 0, 0xFF, 0xFD],
+        }
+    }
+}
+
+pub struct Detect;
+
+impl EncodingStatics for Detect {
+    fn new(options: &str) -> Result<Box<dyn Encoding>, String> {
+        if !options.is_empty() {
+            return Err("invalid options".into());
+        }
+        Ok(Box::new(Detect))
+    }
+
+    fn print_help() {
+        println!("Guesses the encoding of the input the same way as un_auto, and outputs the decoder");
+        println!("to use for it (as a pipeline stage) and how confident the guess is, instead of");
+        println!("decoding it.");
+        println!("(no options)");
+    }
+}
+
+impl Encoding for Detect {
+    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
+        let detection = match input.peek(SAMPLE_SIZE) {
+            Ok([]) => { return None; },
+            Ok(sample) => detect(sample, sample.len() < SAMPLE_SIZE),
+            Err(e) => { return Some(Err(e)); },
+        };
+        // Discard the rest of the input.
+        loop {
+            match input.fill_buf() {
+                Ok([]) => { break; },
+                Ok(buf) => {
+                    let n = buf.len();
+                    input.consume(n);
+                },
+                Err(e) => { return Some(Err(e)); },
+            }
+        }
+        Some(Ok(format!("{}\n", detection).into_bytes()))
+    }
+}
+
+#[test]
+fn test_detect() {
+    let detected = |sample: &[u8]| {
+        let detection = detect(sample, true);
+        match detection.options {
+            "" => detection.decoder.to_owned(),
+            options => format!("{},{}", detection.decoder, options),
+        }
+    };
+    assert_eq!("un_utf8", detected(b"\xEF\xBB\xBFabc"));
+    assert_eq!("un_utf16,le", detected(b"\xFF\xFEa\0"));
+    assert_eq!("un_utf32,le", detected(b"\xFF\xFE\0\0a\0\0\0"));
+    assert_eq!("un_utf8", detected(b"plain ASCII"));
+    assert_eq!("un_utf8", detected("caf\u{E9} cr\u{E8}me br\u{FB}l\u{E9}e".as_bytes()));
+    assert_eq!("un_utf16,be", detected(b"\0h\0e\0l\0l\0o"));
+    assert_eq!("un_windows1252", detected(b"caf\xE9 cr\xE8me br\xFBl\xE9e \x93quoted\x94"));
+    assert_eq!("un_cp437", detected(b"caf\x82 cr\x8Ame br\x96l\x82e"));
+    assert_eq!("un_iso8859,5", detected(b"\xBF\xE0\xD8\xD2\xD5\xE2, \xDC\xD8\xE0"));
+
+    assert!(AutoDecode::new("utf8").is_err());
+    assert!(Detect::new("utf8").is_err());
+
+    // Every detection is a stage that can be given on the command line.
+    for sample in [&b"\0\0\xFE\xFF\0\0\0a"[..], b"\xFF\xFE\0\0a\0\0\0", b"\xFF\xFEa\0", b"\x81"] {
+        let detection = detect(sample, true);
+        assert!(super::get_encoding(detection.decoder, detection.options).is_ok(), "{}", detection);
+    }
+}
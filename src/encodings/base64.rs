@@ -209,6 +209,7 @@ pub struct Base64Encode {
     base64: Base64,
     line_width: Option<usize>,
     output_line_width: usize,
+    pending: Vec<u8>,
 }
 
 fn parse_single_byte(s: &str) -> Result<u8, String> {
@@ -312,6 +313,7 @@ impl EncodingStatics for Base64Encode {
             },
             line_width: width,
             output_line_width: 0,
+            pending: vec![],
         }))
     }
 
@@ -327,43 +329,56 @@ impl EncodingStatics for Base64Encode {
     }
 }
 
-impl Encoding for Base64Encode {
-    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
-        let mut bytes = Vec::<u8>::new();
-        for i in 0..3 {
-            match input.get_byte() {
-                Some(Ok(byte)) => { bytes.push(byte); },
-                Some(Err(e)) => { return Some(Err(CodeError::new("error getting byte")
-                                                            .with_bytes(bytes)
-                                                            .with_inner(e))); },
-                None => {
-                    if i == 0 {
-                        return None;
-                    } else {
-                        break;
-                    }
-                },
-            };
-        }
-        debug!("encoding {} bytes", bytes.len());
-
-        let encoded = self.base64.encode(&bytes);
+impl Base64Encode {
+    /// Break encoded output into lines, if so configured.
+    fn wrap(&mut self, encoded: Vec<u8>) -> Vec<u8> {
+        let line_width = match self.line_width {
+            Some(line_width) => line_width,
+            None => { return encoded; },
+        };
 
-        if let Some(line_width) = self.line_width {
-            if encoded.len() + self.output_line_width >= line_width {
-                let mut out = vec![];
-                out.extend_from_slice(&encoded[0 .. line_width - self.output_line_width]);
+        let mut out = Vec::with_capacity(encoded.len() + encoded.len() / line_width.max(1) + 1);
+        for byte in encoded {
+            out.push(byte);
+            self.output_line_width += 1;
+            if self.output_line_width == line_width {
                 out.push(b'\n');
-                out.extend_from_slice(&encoded[line_width - self.output_line_width ..]);
-                self.output_line_width = encoded.len() - (line_width - self.output_line_width);
-                Some(Ok(out))
-            } else {
-                self.output_line_width += encoded.len();
-                Some(Ok(encoded))
+                self.output_line_width = 0;
             }
-        } else {
-            Some(Ok(encoded))
         }
+        out
+    }
+}
+
+impl Encoding for Base64Encode {
+    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
+        let n = match input.fill_buf() {
+            Ok([]) => { return None; },
+            Ok(buf) => {
+                self.pending.extend_from_slice(buf);
+                buf.len()
+            },
+            Err(e) => { return Some(Err(e)); },
+        };
+        input.consume(n);
+
+        // Encode all the complete groups of 3 bytes, and leave the rest until there's more input,
+        // or until the end, where it gets padded.
+        let complete = self.pending.len() / 3 * 3;
+        debug!("encoding {} bytes", complete);
+        let encoded = self.base64.encode(&self.pending[.. complete]);
+        self.pending.drain(.. complete);
+        Some(Ok(self.wrap(encoded)))
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, CodeError> {
+        if self.pending.is_empty() {
+            return Ok(vec![]);
+        }
+        debug!("encoding final {} bytes", self.pending.len());
+        let encoded = self.base64.encode(&self.pending);
+        self.pending.clear();
+        Ok(self.wrap(encoded))
     }
 }
 
@@ -432,9 +447,9 @@ impl Encoding for Base64Decode {
                     if byte == b'\r' || byte == b'\n' {
                         // Ignore line endings no matter what our ignore_garbage setting says.
                         continue;
-                    } else if (b'A'..=b'Z').contains(&byte)
-                            || (b'a'..=b'z').contains(&byte)
-                            || (b'0'..=b'9').contains(&byte)
+                    } else if byte.is_ascii_uppercase()
+                            || byte.is_ascii_lowercase()
+                            || byte.is_ascii_digit()
                             || byte == self.base64.code62
                             || byte == self.base64.code63
                             || self.base64.pad == Some(byte) {
@@ -486,3 +501,13 @@ impl Encoding for Base64Decode {
         }
     }
 }
+
+#[test]
+fn test_base64_encoder() {
+    let encoding = Base64Encode::new("width=8").unwrap();
+    let chunks = vec![Ok(b"foo".to_vec()), Ok(b"ba".to_vec()), Ok(b"rbaz!".to_vec())];
+    let encoder = Encoder::new(Box::new(chunks.into_iter()), encoding, "base64",
+                               ErrorPolicy::Halt);
+    let output: Vec<u8> = encoder.flat_map(Result::unwrap).collect();
+    assert_eq!(b"Zm9vYmFy\nYmF6IQ==\n".to_vec(), output);
+}
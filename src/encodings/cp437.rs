@@ -56,7 +56,7 @@ impl EncodingStatics for Cp437Encode {
 
     fn print_help() {
         println!("Encodes character data as Codepage 437 (aka IBM437)");
-        println!("Un-mapped characters raise a warning and are replaced with '?'.");
+        println!("Un-mapped characters are an error; the replacement character is '?'.");
         println!("Caveat: many CP437 characters had multiple uses; this mapping is somewhat arbitrary.");
         println!("options:");
         println!("  nonl: encode U+000A as inverted white circle and U+000D as music note, instead of LF and CR");
@@ -86,8 +86,10 @@ impl Encoding for Cp437Encode {
         let mapped = match MAPPING.iter().enumerate().find(|&(_idx, from)| *from == codepoint) {
             Some((idx, _from)) => idx as u8,
             None => {
-                warn!("cannot map Unicode code point U+{:04X} into CP437", codepoint);
-                return Some(Ok(vec![REPLACEMENT]));
+                return Some(Err(CodeError::new(
+                    format!("cannot map Unicode code point U+{:04X} into CP437", codepoint))
+                    .with_bytes(utils::u32_to_bytes(codepoint, true))
+                    .with_unmappable(codepoint)));
             }
         };
 
@@ -122,26 +124,20 @@ impl EncodingStatics for Cp437Decode {
     }
 }
 
-impl Encoding for Cp437Decode {
-    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
-        let byte = match input.get_byte() {
-            Some(Ok(byte)) => byte,
-            Some(Err(e)) => return Some(Err(e)),
-            None => return None,
-        };
-
-        let mut codepoint = MAPPING[byte as usize];
-        if self.newlines && (byte == b'\n' || byte == b'\r') {
-            debug!("preserving {}", if byte == b'\n' { "LF" } else { "CR" });
-            codepoint = byte as u32;
-        } else if codepoint == SAME {
-            debug!("U+{:04X} identity encoding", byte);
-            codepoint = byte as u32;
+impl Cp437Decode {
+    fn decode_byte(&self, byte: u8) -> u32 {
+        let codepoint = MAPPING[byte as usize];
+        if codepoint == SAME || (self.newlines && (byte == b'\n' || byte == b'\r')) {
+            byte as u32
         } else {
-            debug!("{:#04X} maps to U+{:04X}", byte, codepoint);
+            codepoint
         }
+    }
+}
 
-        Some(Ok(utils::u32_to_bytes(codepoint, true)))
+impl Encoding for Cp437Decode {
+    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
+        utils::decode_single_bytes(input, |byte| Ok(self.decode_byte(byte)))
     }
 
     fn replacement(&self) -> Vec<u8> {
@@ -0,0 +1,397 @@
+use super::super::encoding::*;
+use super::{get_encoding, AutoDecode};
+
+/// How much of the input to search for an encoding declaration.
+const PROLOGUE_SIZE: usize = 4096;
+
+/// Encoding labels, as used in declarations, and the decoders (and their options) they mean.
+/// Labels are compared ignoring case, hyphens and underscores.
+const LABELS: [(&str, &str, &str); 39] = [
+    ("utf8", "un_utf8", ""),
+    // A document whose declaration can be read as ASCII can't really be UTF-16, so like web
+    // browsers do, take it to mean UTF-8.
+    ("utf16", "un_utf8", ""),
+    ("utf16le", "un_utf8", ""),
+    ("utf16be", "un_utf8", ""),
+    ("utf7", "un_utf7", ""),
+    // Documents labelled as ASCII or Latin-1 are often really Windows 1252, which is a superset.
+    ("ascii", "un_windows1252", ""),
+    ("usascii", "un_windows1252", ""),
+    ("iso88591", "un_windows1252", ""),
+    ("latin1", "un_windows1252", ""),
+    ("l1", "un_windows1252", ""),
+    ("windows1252", "un_windows1252", ""),
+    ("cp1252", "un_windows1252", ""),
+    ("xcp1252", "un_windows1252", ""),
+    ("iso88592", "un_iso8859", "2"),
+    ("latin2", "un_iso8859", "2"),
+    ("l2", "un_iso8859", "2"),
+    ("iso88593", "un_iso8859", "3"),
+    ("latin3", "un_iso8859", "3"),
+    ("iso88594", "un_iso8859", "4"),
+    ("latin4", "un_iso8859", "4"),
+    ("iso88595", "un_iso8859", "5"),
+    ("cyrillic", "un_iso8859", "5"),
+    ("iso88596", "un_iso8859", "6"),
+    ("arabic", "un_iso8859", "6"),
+    ("iso885915", "un_iso8859", "15"),
+    ("latin9", "un_iso8859", "15"),
+    ("cp437", "un_cp437", ""),
+    ("ibm437", "un_cp437", ""),
+    ("shiftjis", "un_shift_jis", ""),
+    ("sjis", "un_shift_jis", ""),
+    ("xsjis", "un_shift_jis", ""),
+    ("mskanji", "un_shift_jis", ""),
+    ("csshiftjis", "un_shift_jis", ""),
+    ("windows31j", "un_shift_jis", "cp932"),
+    ("cp932", "un_shift_jis", "cp932"),
+    ("eucjp", "un_euc_jp", ""),
+    ("xeucjp", "un_euc_jp", ""),
+    ("iso2022jp", "un_iso2022jp", ""),
+    ("csiso2022jp", "un_iso2022jp", ""),
+];
+
+/// Find the decoder, and its options, for an encoding label.
+pub fn lookup_label(label: &str) -> Option<(&'static str, &'static str)> {
+    let normalized: String = label.chars()
+        .filter(|&c| c != '-' && c != '_')
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    LABELS.iter()
+        .find(|&&(known, _, _)| known == normalized)
+        .map(|&(_, decoder, options)| (decoder, options))
+}
+
+fn is_label_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"-_.:".contains(&byte)
+}
+
+/// Find an encoding declaration in the prologue of a document, which is given with ASCII letters
+/// lowercased, and any non-ASCII characters as bytes above 0x7F. Returns the range of the label.
+///
+/// Recognized are XML declarations (`<?xml version="1.0" encoding="..."?>`), CSS `@charset "...";`
+/// rules, Python-style `coding: ...` or `coding=...` comments in the first two lines, and HTML
+/// `<meta>` tags with a `charset=...` in them.
+pub fn find_declaration(prologue: &[u8]) -> Option<(usize, usize)> {
+    // The label after some '=' or ':' and optional spaces and quotes, starting at `i`.
+    let label_after = |mut i: usize, separators: &[u8]| -> Option<(usize, usize)> {
+        while prologue.get(i) == Some(&b' ') {
+            i += 1;
+        }
+        if !separators.contains(prologue.get(i)?) {
+            return None;
+        }
+        i += 1;
+        while matches!(prologue.get(i), Some(b' ') | Some(b'"') | Some(b'\'')) {
+            i += 1;
+        }
+        let len = prologue[i ..].iter().take_while(|&&b| is_label_byte(b)).count();
+        if len == 0 { None } else { Some((i, i + len)) }
+    };
+    let find = |haystack: &[u8], needle: &[u8]| {
+        haystack.windows(needle.len()).position(|window| window == needle)
+    };
+
+    if prologue.starts_with(b"<?xml") {
+        let end = find(prologue, b"?>").unwrap_or(prologue.len());
+        if let Some(i) = find(&prologue[.. end], b"encoding") {
+            return label_after(i + b"encoding".len(), b"=");
+        }
+    }
+
+    if prologue.starts_with(b"@charset ") {
+        return label_after(b"@charset".len(), b"\"'");
+    }
+
+    let mut line_start = 0;
+    for _ in 0 .. 2 {
+        let line_end = prologue[line_start ..].iter()
+            .position(|&b| b == b'\n')
+            .map(|n| line_start + n)
+            .unwrap_or(prologue.len());
+        let line = &prologue[line_start .. line_end];
+        if line.iter().find(|&&b| b != b' ' && b != b'\t') == Some(&b'#') {
+            if let Some(i) = find(line, b"coding") {
+                if let Some(range) = label_after(line_start + i + b"coding".len(), b":=") {
+                    return Some(range);
+                }
+            }
+        }
+        line_start = line_end + 1;
+        if line_start >= prologue.len() {
+            break;
+        }
+    }
+
+    let mut search_from = 0;
+    while let Some(i) = find(&prologue[search_from ..], b"<meta") {
+        let tag_start = search_from + i;
+        let tag_end = prologue[tag_start ..].iter()
+            .position(|&b| b == b'>')
+            .map(|n| tag_start + n)
+            .unwrap_or(prologue.len());
+        if let Some(j) = find(&prologue[tag_start .. tag_end], b"charset") {
+            if let Some(range) = label_after(tag_start + j + b"charset".len(), b"=") {
+                return Some(range);
+            }
+        }
+        search_from = tag_end;
+    }
+
+    None
+}
+
+/// Make the view of a document's prologue that `find_declaration` works on, from bytes.
+fn ascii_view(bytes: &[u8]) -> Vec<u8> {
+    bytes.iter().map(|b| b.to_ascii_lowercase()).collect()
+}
+
+/// Format a decoder and its options the way they're written in a pipeline.
+fn describe((decoder, options): (&str, &str)) -> String {
+    if options.is_empty() {
+        decoder.to_owned()
+    } else {
+        format!("{},{}", decoder, options)
+    }
+}
+
+pub struct DeclaredDecode {
+    fallback: Option<(&'static str, &'static str)>,
+    inner: Option<Box<dyn Encoding>>,
+}
+
+impl EncodingStatics for DeclaredDecode {
+    fn new(options: &str) -> Result<Box<dyn Encoding>, String> {
+        let fallback = match options {
+            "" => None,
+            _ => match options.strip_prefix("fallback=") {
+                Some(label) => Some(lookup_label(label)
+                    .ok_or_else(|| format!("unknown encoding label {:?}", label))?),
+                None => { return Err("unrecognized option".into()); },
+            },
+        };
+        Ok(Box::new(DeclaredDecode { fallback, inner: None }))
+    }
+
+    fn print_help() {
+        println!("Decodes input into character data (UTF-32BE) using the encoding it declares for");
+        println!("itself: in an XML declaration, an HTML <meta> charset, a CSS @charset rule, or a");
+        println!("Python-style 'coding:' comment, within the first 4 KiB. A byte order mark takes");
+        println!("precedence over the declaration.");
+        println!("Options:");
+        println!("  fallback=<label>: the encoding to use if there's no declaration, or it declares");
+        println!("    an unsupported encoding, which is warned about (e.g. fallback=windows-1252).");
+        println!("    By default the encoding is guessed as by un_auto.");
+    }
+}
+
+impl DeclaredDecode {
+    /// The decoder to use when there's no usable declaration.
+    fn fallback(&self) -> Box<dyn Encoding> {
+        match self.fallback {
+            Some(fallback) => {
+                info!("using {}", describe(fallback));
+                get_encoding(fallback.0, fallback.1).unwrap()
+            },
+            None => {
+                info!("detecting the encoding instead");
+                AutoDecode::new("").unwrap()
+            },
+        }
+    }
+
+    /// Decide on the decoder to use, from a prologue of the input. If the declared encoding isn't
+    /// supported, this is the fallback.
+    fn choose(&self, prologue: &[u8]) -> Box<dyn Encoding> {
+        let has_bom = prologue.starts_with(&[0xEF, 0xBB, 0xBF])
+            || prologue.starts_with(&[0xFE, 0xFF])
+            || prologue.starts_with(&[0xFF, 0xFE]);
+        if has_bom {
+            // un_auto picks the encoding from the BOM, and skips it.
+            info!("found a byte order mark; ignoring any declaration");
+            return AutoDecode::new("").unwrap();
+        }
+        let declared = find_declaration(&ascii_view(prologue))
+            .map(|(start, end)| String::from_utf8_lossy(&prologue[start .. end]).into_owned());
+
+        match declared {
+            Some(label) => match lookup_label(&label) {
+                Some(found) => {
+                    info!("encoding declared as {:?}: using {}", label, describe(found));
+                    get_encoding(found.0, found.1).unwrap()
+                },
+                None => {
+                    // This isn't a problem with the data itself, so it doesn't go through the
+                    // error policy, which could put a replacement in the output for it.
+                    warn!("declared encoding {:?} is not supported", label);
+                    self.fallback()
+                },
+            },
+            None => {
+                info!("no encoding declaration");
+                self.fallback()
+            },
+        }
+    }
+}
+
+impl Encoding for DeclaredDecode {
+    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
+        if self.inner.is_none() {
+            let inner = match input.peek(PROLOGUE_SIZE) {
+                Ok([]) => { return None; },
+                Ok(prologue) => self.choose(prologue),
+                Err(e) => { return Some(Err(e)); },
+            };
+            self.inner = Some(inner);
+        }
+        self.inner.as_mut().unwrap().next(input)
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, CodeError> {
+        match self.inner {
+            Some(ref mut inner) => inner.finish(),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn replacement(&self) -> Vec<u8> {
+        match self.inner {
+            Some(ref inner) => inner.replacement(),
+            None => vec![0, 0, 0xFF, 0xFD],
+        }
+    }
+}
+
+pub struct Redeclare {
+    label: String,
+    done: bool,
+}
+
+impl EncodingStatics for Redeclare {
+    fn new(options: &str) -> Result<Box<dyn Encoding>, String> {
+        if options.is_empty() {
+            return Err("an encoding label is required".into());
+        }
+        if !options.bytes().all(is_label_byte) {
+            return Err(format!("invalid encoding label {:?}", options));
+        }
+        if lookup_label(options).is_none() {
+            warn!("{:?} is not a known encoding label", options);
+        }
+        Ok(Box::new(Redeclare { label: options.to_owned(), done: false }))
+    }
+
+    fn print_help() {
+        println!("Changes the encoding declared in character data (of the kinds recognized by");
+        println!("un_declared) to the given one, so that a document can be re-encoded and still");
+        println!("declare the right encoding. Input without a declaration is passed through.");
+        println!("Options:");
+        println!("  the encoding label to declare, e.g. utf-8");
+    }
+}
+
+impl Encoding for Redeclare {
+    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
+        if self.done {
+            let out = match input.fill_buf() {
+                Ok([]) => { return None; },
+                Ok(buf) => buf.to_vec(),
+                Err(e) => { return Some(Err(e)); },
+            };
+            input.consume(out.len());
+            return Some(Ok(out));
+        }
+        self.done = true;
+
+        let prologue = match input.peek(PROLOGUE_SIZE * 4) {
+            Ok([]) => { return None; },
+            Ok(prologue) => &prologue[.. prologue.len() - prologue.len() % 4],
+            Err(e) => { return Some(Err(e)); },
+        };
+        let chars: Vec<u32> = prologue.chunks_exact(4)
+            .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect();
+        let view: Vec<u8> = chars.iter()
+            .map(|&c| if c < 0x80 { (c as u8).to_ascii_lowercase() } else { 0x80 })
+            .collect();
+
+        let mut out = Vec::with_capacity(prologue.len());
+        match find_declaration(&view) {
+            Some((start, end)) => {
+                debug!("replacing declared encoding with {:?}", self.label);
+                out.extend_from_slice(&prologue[.. start * 4]);
+                for c in self.label.chars() {
+                    out.extend_from_slice(&(c as u32).to_be_bytes());
+                }
+                out.extend_from_slice(&prologue[end * 4 ..]);
+            },
+            None => {
+                info!("no encoding declaration found to replace");
+                out.extend_from_slice(prologue);
+            },
+        }
+        let n = prologue.len();
+        input.consume(n);
+        Some(Ok(out))
+    }
+}
+
+#[test]
+fn test_find_declaration() {
+    let declared = |doc: &str| {
+        find_declaration(&ascii_view(doc.as_bytes())).map(|(start, end)| doc[start .. end].to_owned())
+    };
+    assert_eq!(Some("ISO-8859-2"),
+               declared("<?xml version=\"1.0\" encoding=\"ISO-8859-2\"?>\n<a/>").as_deref());
+    assert_eq!(Some("shift_jis"),
+               declared("<!DOCTYPE html>\n<html><head><meta charset=shift_jis>").as_deref());
+    assert_eq!(Some("windows-1252"),
+               declared("<html><meta http-equiv=\"Content-Type\" \
+                         content=\"text/html; charset=windows-1252\">").as_deref());
+    assert_eq!(Some("utf-8"), declared("@charset \"utf-8\";\nbody {}").as_deref());
+    assert_eq!(Some("latin-1"),
+               declared("#!/usr/bin/python\n# -*- coding: latin-1 -*-\n").as_deref());
+    assert_eq!(None, declared("import os\n\n# coding: latin-1\n"));
+    assert_eq!(None, declared("<p>charset=utf-8</p>"));
+    assert_eq!(Some(("un_iso8859", "2")), lookup_label("Latin_2"));
+}
+
+#[test]
+fn test_declared_decode() {
+    use crate::pipeline::Pipeline;
+
+    let convert = |stages: &[&str], input: &[u8], error_policy| {
+        let pipeline = Pipeline::parse(stages, error_policy).unwrap();
+        let chunks = Box::new(Some(Ok(input.to_vec())).into_iter());
+        pipeline.build(chunks).unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .ok()
+            .map(|chunks| String::from_utf8(chunks.concat()).unwrap())
+    };
+
+    let latin2 = b"<?xml version='1.0' encoding='iso-8859-2'?>\n<a>\xB1</a>";
+    assert_eq!(Some("<?xml version='1.0' encoding='iso-8859-2'?>\n<a>\u{105}</a>".to_owned()),
+               convert(&["un_declared", "utf8"], latin2, ErrorPolicy::Halt));
+    assert_eq!(Some("<?xml version='1.0' encoding='UTF-8'?>\n<a>\u{105}</a>".to_owned()),
+               convert(&["un_declared", "redeclare,UTF-8", "utf8"], latin2, ErrorPolicy::Halt));
+
+    // A BOM wins over the declaration, and over the fallback.
+    let bom = b"\xEF\xBB\xBF<meta charset=latin1>\xC3\xA9";
+    assert_eq!(Some("<meta charset=latin1>\u{E9}".to_owned()),
+               convert(&["un_declared,fallback=latin2", "utf8"], bom, ErrorPolicy::Halt));
+
+    // An unsupported encoding is only warned about, and the fallback is used; it isn't an error
+    // in the data.
+    let unknown = b"<meta charset=foo>\xE9";
+    assert_eq!(Some("<meta charset=foo>\u{E9}".to_owned()),
+               convert(&["un_declared,fallback=latin1", "utf8"], unknown, ErrorPolicy::Replace));
+    assert_eq!(Some("<meta charset=foo>\u{E9}".to_owned()),
+               convert(&["un_declared,fallback=latin1", "utf8"], unknown, ErrorPolicy::Halt));
+    assert!(convert(&["un_declared", "utf8"], unknown, ErrorPolicy::Halt).is_some());
+
+    // Without a declaration, there's nothing to replace.
+    assert_eq!(Some("plain \u{E9}".to_owned()),
+               convert(&["un_windows1252", "redeclare,utf-8", "utf8"], b"plain \xE9",
+                       ErrorPolicy::Halt));
+}
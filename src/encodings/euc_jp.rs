@@ -0,0 +1,248 @@
+use super::super::encoding::*;
+use super::jis;
+use super::utils;
+
+const REPLACEMENT: u8 = b'?';
+
+/// Single shift 2: the next byte is a half-width katakana.
+const SS2: u8 = 0x8E;
+
+/// Single shift 3: the next two bytes are a JIS X 0212 character.
+const SS3: u8 = 0x8F;
+
+/// Bytes in a double-byte character are the row or cell number plus this.
+const OFFSET: u8 = 0xA0;
+
+pub struct EucJpEncode;
+
+impl EncodingStatics for EucJpEncode {
+    fn new(options: &str) -> Result<Box<dyn Encoding>, String> {
+        if !options.is_empty() {
+            return Err("invalid options".into());
+        }
+        Ok(Box::new(EucJpEncode))
+    }
+
+    fn print_help() {
+        println!("Encodes character data as EUC-JP: ASCII, JIS X 0208 (with the NEC special");
+        println!("characters in row 13), half-width katakana, and JIS X 0212 for anything else. The");
+        println!("yen sign and overline are encoded as 0x5C and 0x7E, as in JIS-Roman.");
+        println!("Un-mapped characters are an error; the replacement character is '?'.");
+        println!("(no options)");
+    }
+}
+
+impl EucJpEncode {
+    fn encode_codepoint(codepoint: u32, out: &mut Vec<u8>) -> Result<(), CodeError> {
+        match codepoint {
+            0 ..= 0x7F => {
+                out.push(codepoint as u8);
+                return Ok(());
+            },
+            // As WHATWG does, since EUC-JP is often read with JIS-Roman in place of ASCII.
+            0xA5 => {
+                out.push(0x5C); // YEN SIGN
+                return Ok(());
+            },
+            0x203E => {
+                out.push(0x7E); // OVERLINE
+                return Ok(());
+            },
+            _ => (),
+        }
+
+        if let Some(idx) = jis::HALFWIDTH.iter().position(|&c| c as u32 == codepoint) {
+            debug!("U+{:04X}: half-width katakana", codepoint);
+            out.extend_from_slice(&[SS2, 0xA1 + idx as u8]);
+            return Ok(());
+        }
+
+        if let Some((row, cell)) = jis::jis0208_nec_encode(codepoint) {
+            debug!("U+{:04X} is JIS X 0208 row {} cell {}", codepoint, row, cell);
+            out.extend_from_slice(&[row + OFFSET, cell + OFFSET]);
+            return Ok(());
+        }
+        if let Some((row, cell)) = jis::jis0212_encode(codepoint) {
+            debug!("U+{:04X} is JIS X 0212 row {} cell {}", codepoint, row, cell);
+            out.extend_from_slice(&[SS3, row + OFFSET, cell + OFFSET]);
+            return Ok(());
+        }
+
+        Err(CodeError::new(format!("cannot map Unicode code point U+{:04X} into EUC-JP", codepoint))
+            .with_bytes(utils::u32_to_bytes(codepoint, true))
+            .with_unmappable(codepoint))
+    }
+}
+
+impl Encoding for EucJpEncode {
+    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
+        utils::encode_codepoints(input, Self::encode_codepoint)
+    }
+
+    fn replacement(&self) -> Vec<u8> {
+        vec![REPLACEMENT]
+    }
+}
+
+pub struct EucJpDecode;
+
+impl EncodingStatics for EucJpDecode {
+    fn new(options: &str) -> Result<Box<dyn Encoding>, String> {
+        if !options.is_empty() {
+            return Err("invalid options".into());
+        }
+        Ok(Box::new(EucJpDecode))
+    }
+
+    fn print_help() {
+        println!("Decodes EUC-JP into character data. JIS X 0208 includes the NEC special");
+        println!("characters in row 13, as in the WHATWG Encoding Standard.");
+        println!("(no options)");
+    }
+}
+
+impl EucJpDecode {
+    /// Read the next byte of a multi-byte character, which must be in the given range. A bad
+    /// byte that is ASCII is left to be decoded as itself.
+    fn continuation_byte(input: &mut dyn EncodingInput, bytes: &mut Vec<u8>, min: u8, max: u8)
+            -> Result<u8, CodeError> {
+        let byte = match input.get_byte() {
+            Some(Ok(byte)) => byte,
+            Some(Err(e)) => {
+                return Err(CodeError::new("incomplete EUC-JP character")
+                    .with_bytes(bytes.clone())
+                    .with_inner(e));
+            },
+            None => {
+                return Err(CodeError::new("incomplete EUC-JP character due to EOF")
+                    .with_bytes(bytes.clone()));
+            },
+        };
+        if byte < min || byte > max {
+            if byte < 0x80 {
+                input.unget_byte(byte);
+            } else {
+                bytes.push(byte);
+            }
+            return Err(CodeError::new(format!("illegal byte {:#x} in EUC-JP character", byte))
+                .with_bytes(bytes.clone()));
+        }
+        bytes.push(byte);
+        Ok(byte)
+    }
+
+    fn decode_char(input: &mut dyn EncodingInput, first_byte: u8) -> Result<u32, CodeError> {
+        let mut bytes = vec![first_byte];
+        match first_byte {
+            SS2 => {
+                let byte = Self::continuation_byte(input, &mut bytes, 0xA1, 0xDF)?;
+                debug!("{:#x}: half-width katakana", byte);
+                Ok(jis::HALFWIDTH[(byte - 0xA1) as usize] as u32)
+            },
+            SS3 => {
+                let row = Self::continuation_byte(input, &mut bytes, 0xA1, 0xFE)? - OFFSET;
+                let cell = Self::continuation_byte(input, &mut bytes, 0xA1, 0xFE)? - OFFSET;
+                debug!("JIS X 0212 row {} cell {}", row, cell);
+                jis::jis0212_decode(row, cell).ok_or_else(|| {
+                    CodeError::new(format!("no character at JIS X 0212 row {} cell {}", row, cell))
+                        .with_bytes(bytes)
+                })
+            },
+            0xA1 ..= 0xFE => {
+                let row = first_byte - OFFSET;
+                let cell = Self::continuation_byte(input, &mut bytes, 0xA1, 0xFE)? - OFFSET;
+                debug!("JIS X 0208 row {} cell {}", row, cell);
+                jis::jis0208_nec_decode(row, cell).ok_or_else(|| {
+                    CodeError::new(format!("no character at JIS X 0208 row {} cell {}", row, cell))
+                        .with_bytes(bytes)
+                })
+            },
+            _ => Err(CodeError::new("illegal first EUC-JP byte").with_bytes(bytes)),
+        }
+    }
+}
+
+impl Encoding for EucJpDecode {
+    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
+        // Fast path: decode any run of ASCII all in one go.
+        match input.fill_buf() {
+            Ok(buf) => {
+                let n = buf.iter().take_while(|&&byte| byte < 0x80).count();
+                if n > 0 {
+                    let mut out = Vec::with_capacity(n * 4);
+                    for &byte in &buf[.. n] {
+                        out.extend_from_slice(&[0, 0, 0, byte]);
+                    }
+                    input.consume(n);
+                    return Some(Ok(out));
+                }
+            },
+            Err(e) => { return Some(Err(e)); },
+        }
+
+        let first_byte = match input.get_byte() {
+            Some(Ok(byte)) => byte,
+            Some(Err(e)) => { return Some(Err(e)); },
+            None => { return None; },
+        };
+        debug!("{:#x}: first byte of multi-byte character", first_byte);
+        Some(Self::decode_char(input, first_byte).map(|codepoint| utils::u32_to_bytes(codepoint, true)))
+    }
+
+    fn replacement(&self) -> Vec<u8> {
+        utils::unicode_replacement()
+    }
+}
+
+#[test]
+fn test_euc_jp() {
+    let convert = |encoding: Box<dyn Encoding>, input: Vec<u8>, error_policy| {
+        let encoder = Encoder::new(Box::new(Some(Ok(input)).into_iter()), encoding, "euc_jp",
+                                   error_policy);
+        encoder.collect::<Result<Vec<_>, _>>().map(|chunks| chunks.concat())
+    };
+    let chars = |text: &str| text.chars().flat_map(|c| (c as u32).to_be_bytes()).collect::<Vec<u8>>();
+
+    assert!(EucJpEncode::new("cp932").is_err());
+    assert!(EucJpDecode::new("cp932").is_err());
+
+    let bytes = b"\xC6\xFC\xCB\xDC\xB8\xEC \x8E\xB1\x8F\xB0\xA1 ~\\";
+    let text = "\u{65E5}\u{672C}\u{8A9E} \u{FF71}\u{4E02} ~\\";
+    assert_eq!(chars(text),
+               convert(EucJpDecode::new("").unwrap(), bytes.to_vec(), ErrorPolicy::Halt).unwrap());
+    assert_eq!(bytes.to_vec(),
+               convert(EucJpEncode::new("").unwrap(), chars(text), ErrorPolicy::Halt).unwrap());
+
+    assert_eq!(b"\x5C\x7E\xA1\xDD".to_vec(),
+               convert(EucJpEncode::new("").unwrap(), chars("\u{A5}\u{203E}\u{2212}"),
+                       ErrorPolicy::Halt).unwrap());
+
+    // The NEC special characters are in row 13.
+    assert_eq!(chars("\u{2460}\u{2160}"),
+               convert(EucJpDecode::new("").unwrap(), b"\xAD\xA1\xAD\xB5".to_vec(),
+                       ErrorPolicy::Halt).unwrap());
+    assert_eq!(b"\xAD\xA1\xAD\xB5".to_vec(),
+               convert(EucJpEncode::new("").unwrap(), chars("\u{2460}\u{2160}"),
+                       ErrorPolicy::Halt).unwrap());
+
+    // Bad bytes each get a replacement, without swallowing ASCII.
+    let bad = b"\x8E\xE0a\xA1b\x8F\xA1";
+    assert_eq!(chars("\u{FFFD}a\u{FFFD}b\u{FFFD}"),
+               convert(EucJpDecode::new("").unwrap(), bad.to_vec(), ErrorPolicy::Replace).unwrap());
+
+    // Every JIS X 0212 character round-trips, unless it's also in JIS X 0208.
+    for row in 1 ..= jis::ROW_SIZE {
+        for cell in 1 ..= jis::ROW_SIZE {
+            let bytes = vec![SS3, row + OFFSET, cell + OFFSET];
+            let decoded = match convert(EucJpDecode::new("").unwrap(), bytes.clone(), ErrorPolicy::Halt) {
+                Ok(decoded) => decoded,
+                Err(_) => continue,
+            };
+            let encoded = convert(EucJpEncode::new("").unwrap(), decoded.clone(), ErrorPolicy::Halt)
+                .unwrap();
+            if encoded[0] == SS3 {
+                assert_eq!(bytes, encoded);
+            }
+        }
+    }
+}
@@ -2,7 +2,6 @@ use super::super::encoding::*;
 
 pub struct HexEncode {
     uppercase: bool,
-    done: bool,
 }
 
 impl HexEncode {
@@ -27,7 +26,6 @@ impl EncodingStatics for HexEncode {
     fn new(_options: &str) -> Result<Box<dyn Encoding>, String> {
         Ok(Box::new(HexEncode {
             uppercase: false,
-            done: false,
         }))
     }
 
@@ -40,23 +38,25 @@ impl EncodingStatics for HexEncode {
 
 impl Encoding for HexEncode {
     fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
-        match input.get_byte() {
-            Some(Ok(byte)) => {
-                let (high, low) = self.hex_chars(byte);
-                Some(Ok(vec![high, low, b' ']))
-            },
-            Some(Err(e)) => Some(Err(e)),
-            None => {
-                if self.done {
-                    self.done = false;
-                    None
-                } else {
-                    self.done = true;
-                    Some(Ok(vec![b'\n']))
+        match input.fill_buf() {
+            Ok(buf) if !buf.is_empty() => {
+                let mut out = Vec::with_capacity(buf.len() * 3);
+                for &byte in buf {
+                    let (high, low) = self.hex_chars(byte);
+                    out.extend_from_slice(&[high, low, b' ']);
                 }
-            }
+                let n = buf.len();
+                input.consume(n);
+                Some(Ok(out))
+            },
+            Err(e) => Some(Err(e)),
+            Ok(_) => None,
         }
     }
+
+    fn finish(&mut self) -> Result<Vec<u8>, CodeError> {
+        Ok(vec![b'\n'])
+    }
 }
 
 pub struct HexDecode;
@@ -84,7 +84,7 @@ impl Encoding for HexDecode {
                     let value = if c == ' ' || c == '\t' || c == '\r' || c == '\n' {
                         // skip whitespace
                         continue;
-                    } else if ('0'..='9').contains(&c) {
+                    } else if c.is_ascii_digit() {
                         byte - b'0'
                     } else if ('a'..='f').contains(&c) {
                         byte - b'a' + 10
@@ -0,0 +1,307 @@
+use super::super::encoding::*;
+use super::jis;
+use super::utils;
+
+const ESC: u8 = 0x1B;
+
+/// The character sets which ISO-2022-JP switches between.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum CharSet {
+    /// ASCII, selected by ESC ( B. Every line starts out in ASCII.
+    Ascii,
+    /// JIS X 0201 Roman, selected by ESC ( J: ASCII but with a yen sign for 0x5C and an overline
+    /// for 0x7E.
+    Roman,
+    /// JIS X 0201 half-width katakana, selected by ESC ( I. Not part of ISO-2022-JP proper, so
+    /// only decoded.
+    Katakana,
+    /// JIS X 0208, selected by ESC $ B (or the older ESC $ @), two bytes to a character.
+    Jis0208,
+}
+
+impl CharSet {
+    fn escape_sequence(self) -> &'static [u8] {
+        match self {
+            CharSet::Ascii => b"\x1B(B",
+            CharSet::Roman => b"\x1B(J",
+            CharSet::Katakana => b"\x1B(I",
+            CharSet::Jis0208 => b"\x1B$B",
+        }
+    }
+
+    fn from_escape_sequence(sequence: &[u8]) -> Option<CharSet> {
+        match sequence {
+            b"(B" => Some(CharSet::Ascii),
+            b"(J" => Some(CharSet::Roman),
+            b"(I" => Some(CharSet::Katakana),
+            b"$B" | b"$@" => Some(CharSet::Jis0208),
+            _ => None,
+        }
+    }
+}
+
+pub struct Iso2022JpEncode {
+    /// The character set currently shifted into.
+    charset: CharSet,
+}
+
+impl EncodingStatics for Iso2022JpEncode {
+    fn new(options: &str) -> Result<Box<dyn Encoding>, String> {
+        if !options.is_empty() {
+            return Err("invalid options".into());
+        }
+        Ok(Box::new(Iso2022JpEncode { charset: CharSet::Ascii }))
+    }
+
+    fn print_help() {
+        println!("Encodes character data as ISO-2022-JP (RFC 1468): ASCII, JIS X 0201 Roman for the");
+        println!("yen sign and overline, and JIS X 0208 (with the NEC special characters in row 13),");
+        println!("switching between them with escape sequences. Shifts back to ASCII before the end");
+        println!("of every line and of the output.");
+        println!("Un-mapped characters (including half-width katakana) are an error; the replacement");
+        println!("character is '?'.");
+        println!("(no options)");
+    }
+}
+
+impl Iso2022JpEncode {
+    fn encode_codepoint(&mut self, codepoint: u32, out: &mut Vec<u8>) -> Result<(), CodeError> {
+        let (charset, bytes) = match codepoint {
+            0xA5 => (CharSet::Roman, vec![0x5C]), // YEN SIGN
+            0x203E => (CharSet::Roman, vec![0x7E]), // OVERLINE
+            0x1B => {
+                // It would be taken as the start of an escape sequence.
+                return Err(CodeError::new("the escape character can't be encoded in ISO-2022-JP")
+                    .with_bytes(utils::u32_to_bytes(codepoint, true))
+                    .with_unmappable(codepoint));
+            },
+            // Roman is the same as ASCII apart from those, so there's no need to shift out of it,
+            // except at the end of a line.
+            0x5C | 0x7E | 0x0A | 0x0D => (CharSet::Ascii, vec![codepoint as u8]),
+            0 ..= 0x7F if self.charset == CharSet::Roman => {
+                (CharSet::Roman, vec![codepoint as u8])
+            },
+            0 ..= 0x7F => (CharSet::Ascii, vec![codepoint as u8]),
+            _ => {
+                match jis::jis0208_nec_encode(codepoint) {
+                    Some((row, cell)) => {
+                        debug!("U+{:04X} is JIS X 0208 row {} cell {}", codepoint, row, cell);
+                        (CharSet::Jis0208, vec![row + 0x20, cell + 0x20])
+                    },
+                    None => {
+                        return Err(CodeError::new(
+                            format!("cannot map Unicode code point U+{:04X} into ISO-2022-JP",
+                                    codepoint))
+                            .with_bytes(utils::u32_to_bytes(codepoint, true))
+                            .with_unmappable(codepoint));
+                    },
+                }
+            },
+        };
+
+        if charset != self.charset {
+            debug!("shifting to {:?}", charset);
+            out.extend_from_slice(charset.escape_sequence());
+            self.charset = charset;
+        }
+        out.extend_from_slice(&bytes);
+        Ok(())
+    }
+}
+
+impl Encoding for Iso2022JpEncode {
+    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
+        utils::encode_codepoints(input, |codepoint, out| self.encode_codepoint(codepoint, out))
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, CodeError> {
+        Ok(self.reset())
+    }
+
+    fn reset(&mut self) -> Vec<u8> {
+        if self.charset == CharSet::Ascii {
+            vec![]
+        } else {
+            debug!("shifting to {:?}", CharSet::Ascii);
+            self.charset = CharSet::Ascii;
+            CharSet::Ascii.escape_sequence().to_vec()
+        }
+    }
+}
+
+pub struct Iso2022JpDecode {
+    charset: CharSet,
+}
+
+impl EncodingStatics for Iso2022JpDecode {
+    fn new(options: &str) -> Result<Box<dyn Encoding>, String> {
+        if !options.is_empty() {
+            return Err("invalid options".into());
+        }
+        Ok(Box::new(Iso2022JpDecode { charset: CharSet::Ascii }))
+    }
+
+    fn print_help() {
+        println!("Decodes ISO-2022-JP into character data. Also accepts JIS X 0201 half-width");
+        println!("katakana (ESC ( I), and the NEC special characters in row 13 of JIS X 0208, as in");
+        println!("the WHATWG Encoding Standard. A line break always shifts back to ASCII.");
+        println!("(no options)");
+    }
+}
+
+impl Iso2022JpDecode {
+    /// Read the rest of an escape sequence and switch to the character set it selects.
+    fn escape(&mut self, input: &mut dyn EncodingInput) -> Result<(), CodeError> {
+        let sequence = input.peek(2)?.to_vec();
+        match CharSet::from_escape_sequence(&sequence) {
+            Some(charset) => {
+                input.consume(2);
+                debug!("shifting to {:?}", charset);
+                self.charset = charset;
+                Ok(())
+            },
+            None => {
+                // Leave whatever follows the ESC to be decoded normally, so it isn't part of the
+                // error either.
+                debug!("malformed escape sequence: ESC followed by {:x?}", sequence);
+                Err(CodeError::new("malformed escape sequence").with_bytes(vec![ESC]))
+            },
+        }
+    }
+}
+
+impl Encoding for Iso2022JpDecode {
+    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
+        let first_byte = match input.get_byte() {
+            Some(Ok(byte)) => byte,
+            Some(Err(e)) => { return Some(Err(e)); },
+            None => { return None; },
+        };
+
+        if first_byte == ESC {
+            return Some(self.escape(input).map(|()| vec![]));
+        }
+        if first_byte == b'\n' || first_byte == b'\r' {
+            if self.charset != CharSet::Ascii {
+                debug!("line break in {:?}; shifting to ASCII", self.charset);
+                self.charset = CharSet::Ascii;
+            }
+            return Some(Ok(vec![0, 0, 0, first_byte]));
+        }
+        if first_byte >= 0x80 {
+            return Some(Err(CodeError::new("illegal byte in ISO-2022-JP")
+                .with_bytes(vec![first_byte])));
+        }
+
+        let codepoint = match (self.charset, first_byte) {
+            (CharSet::Ascii, _) => u32::from(first_byte),
+            (CharSet::Roman, 0x5C) => 0xA5, // YEN SIGN
+            (CharSet::Roman, 0x7E) => 0x203E, // OVERLINE
+            (CharSet::Roman, _) => u32::from(first_byte),
+            (CharSet::Katakana, 0x21 ..= 0x5F) => jis::HALFWIDTH[(first_byte - 0x21) as usize] as u32,
+            (CharSet::Jis0208, 0x21 ..= 0x7E) => {
+                let second_byte = match input.get_byte() {
+                    Some(Ok(byte)) => byte,
+                    Some(Err(e)) => { return Some(Err(e)); },
+                    None => {
+                        return Some(Err(CodeError::new("incomplete JIS X 0208 character due to EOF")
+                            .with_bytes(vec![first_byte])));
+                    },
+                };
+                if !(0x21 ..= 0x7E).contains(&second_byte) {
+                    input.unget_byte(second_byte);
+                    return Some(Err(CodeError::new("incomplete JIS X 0208 character")
+                        .with_bytes(vec![first_byte])));
+                }
+                let (row, cell) = (first_byte - 0x20, second_byte - 0x20);
+                debug!("JIS X 0208 row {} cell {}", row, cell);
+                match jis::jis0208_nec_decode(row, cell) {
+                    Some(codepoint) => codepoint,
+                    None => {
+                        return Some(Err(CodeError::new(
+                            format!("no character at JIS X 0208 row {} cell {}", row, cell))
+                            .with_bytes(vec![first_byte, second_byte])));
+                    },
+                }
+            },
+            (charset, _) => {
+                return Some(Err(CodeError::new(format!("illegal byte in {:?}", charset))
+                    .with_bytes(vec![first_byte])));
+            },
+        };
+        Some(Ok(utils::u32_to_bytes(codepoint, true)))
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, CodeError> {
+        if self.charset != CharSet::Ascii {
+            warn!("input ended in {:?} instead of ASCII", self.charset);
+        }
+        Ok(vec![])
+    }
+
+    fn replacement(&self) -> Vec<u8> {
+        utils::unicode_replacement()
+    }
+}
+
+#[test]
+fn test_iso2022jp() {
+    let convert = |encoding: Box<dyn Encoding>, input: Vec<u8>, error_policy| {
+        let encoder = Encoder::new(Box::new(Some(Ok(input)).into_iter()), encoding, "iso2022jp",
+                                   error_policy);
+        encoder.collect::<Result<Vec<_>, _>>().map(|chunks| chunks.concat())
+    };
+    let chars = |text: &str| text.chars().flat_map(|c| (c as u32).to_be_bytes()).collect::<Vec<u8>>();
+
+    assert!(Iso2022JpEncode::new("cp932").is_err());
+    assert!(Iso2022JpDecode::new("cp932").is_err());
+
+    assert_eq!(b"\x1B$B!]\x1B(B".to_vec(),
+               convert(Iso2022JpEncode::new("").unwrap(), chars("\u{2212}"), ErrorPolicy::Halt)
+               .unwrap());
+
+    let text = "a\u{65E5}\u{672C}\nb\u{A5}c\\\u{8A9E}";
+    let bytes = b"a\x1B$BF|K\\\x1B(B\nb\x1B(J\\c\x1B(B\\\x1B$B8l\x1B(B";
+    assert_eq!(bytes.to_vec(),
+               convert(Iso2022JpEncode::new("").unwrap(), chars(text), ErrorPolicy::Halt).unwrap());
+    assert_eq!(chars(text),
+               convert(Iso2022JpDecode::new("").unwrap(), bytes.to_vec(), ErrorPolicy::Halt).unwrap());
+
+    // The NEC special characters are in row 13, as in EUC-JP.
+    assert_eq!(chars("\u{2460}"),
+               convert(Iso2022JpDecode::new("").unwrap(), b"\x1B$B-!\x1B(B".to_vec(),
+                       ErrorPolicy::Halt).unwrap());
+    assert_eq!(b"\x1B$B-!\x1B(B".to_vec(),
+               convert(Iso2022JpEncode::new("").unwrap(), chars("\u{2460}"), ErrorPolicy::Halt)
+               .unwrap());
+
+    // State carries over from one chunk of input to the next.
+    let chunks = vec![Ok(b"\x1B$".to_vec()), Ok(b"BF".to_vec()), Ok(b"|\n\\".to_vec())];
+    let encoder = Encoder::new(Box::new(chunks.into_iter()), Iso2022JpDecode::new("").unwrap(),
+                               "un_iso2022jp", ErrorPolicy::Halt);
+    assert_eq!(chars("\u{65E5}\n\\"),
+               encoder.collect::<Result<Vec<_>, _>>().unwrap().concat());
+
+    let e = convert(Iso2022JpDecode::new("").unwrap(), b"x\x1B(Zy".to_vec(), ErrorPolicy::Halt)
+        .unwrap_err();
+    assert_eq!(Some(&[ESC][..]), e.bad_bytes());
+    assert_eq!(chars("x\u{FFFD}(Zy"),
+               convert(Iso2022JpDecode::new("").unwrap(), b"x\x1B(Zy".to_vec(), ErrorPolicy::Replace)
+               .unwrap());
+
+    // A replacement in the middle of JIS X 0208 shifts back to ASCII for the '?'.
+    assert_eq!(b"\x1B$BF|\x1B(B?\x1B$BF|\x1B(B".to_vec(),
+               convert(Iso2022JpEncode::new("").unwrap(), chars("\u{65E5}\u{FF71}\u{65E5}"),
+                       ErrorPolicy::Replace).unwrap());
+
+    // So does a replacement of one's own, which is encoded carrying on from where it's needed.
+    let replace = |replacement: &str| {
+        let stage = format!("iso2022jp,replace={}", replacement);
+        let pipeline = crate::pipeline::Pipeline::parse(&[stage], ErrorPolicy::Replace).unwrap();
+        let input = chars("\u{65E5}\u{FF71}\u{65E5}");
+        pipeline.build(Box::new(Some(Ok(input)).into_iter())).unwrap()
+            .collect::<Result<Vec<_>, _>>().unwrap().concat()
+    };
+    assert_eq!(b"\x1B$BF|\x1B(BX\x1B$BF|\x1B(B".to_vec(), replace("X"));
+    assert_eq!(b"\x1B$BF|\".F|\x1B(B".to_vec(), replace("\u{3013}"));
+}
@@ -127,8 +127,8 @@ impl EncodingStatics for Iso8859Encode {
     }
 
     fn print_help() {
-        println!("Encodes character data as ISO 8859-<N>. Un-mapped characters raise a warning,");
-        println!("  and are replaced with '?'.");
+        println!("Encodes character data as ISO 8859-<N>. Un-mapped characters are an error;");
+        println!("  the replacement character is '?'.");
         println!("Options:");
         println!("  a number 1-11 or 13-16, specifying the ISO 8859 part to use.");
     }
@@ -137,9 +137,10 @@ impl EncodingStatics for Iso8859Encode {
 impl Iso8859Encode {
     #[allow(clippy::unnecessary_wraps)] // wraps are delicious
     fn unmapped(&self, codepoint: u32) -> Option<Result<Vec<u8>, CodeError>> {
-        warn!("cannot map Unicode code point U+{:04X} into ISO 8859-{}",
-              codepoint, self.part);
-        Some(Ok(vec![REPLACEMENT]))
+        Some(Err(CodeError::new(format!("cannot map Unicode code point U+{:04X} into ISO 8859-{}",
+                                        codepoint, self.part))
+                 .with_bytes(utils::u32_to_bytes(codepoint, true))
+                 .with_unmappable(codepoint)))
     }
 }
 
@@ -189,6 +190,10 @@ impl Encoding for Iso8859Encode {
         debug!("U+{:04X} maps to {:#04X}", codepoint, mapped);
         Some(Ok(vec![mapped]))
     }
+
+    fn replacement(&self) -> Vec<u8> {
+        vec![REPLACEMENT]
+    }
 }
 
 pub struct Iso8859Decode {
@@ -208,19 +213,13 @@ impl EncodingStatics for Iso8859Decode {
     }
 }
 
-impl Encoding for Iso8859Decode {
-    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
-        let byte = match input.get_byte() {
-            Some(Ok(byte)) => byte,
-            Some(Err(e)) => { return Some(Err(e)); },
-            None => { return None; }
-        };
-
+impl Iso8859Decode {
+    fn decode_byte(&self, byte: u8) -> Result<u32, CodeError> {
         if byte < 0xA1 {
-            return Some(Ok(utils::u32_to_bytes(byte as u32, true)));
+            return Ok(byte as u32);
         }
 
-        let codepoint = if byte < 0xA1 || self.part == 1 {
+        let codepoint = if self.part == 1 {
             byte as u32
         } else if self.part == 15 {
             match MAPPING_15.iter().find(|&&(from, _to)| from == byte) {
@@ -233,14 +232,20 @@ impl Encoding for Iso8859Decode {
                 UNDEF => {
                     let msg = format!("undefined ISO 8859-{} code unit {:#04X}", self.part, byte);
                     error!("{}", msg);
-                    return Some(Err(CodeError::new(msg).with_bytes(vec![byte])));
+                    return Err(CodeError::new(msg).with_bytes(vec![byte]));
                 },
                 codepoint => codepoint
             }
         };
 
         debug!("{:#04X} maps to U+{:04X}", byte, codepoint);
-        Some(Ok(utils::u32_to_bytes(codepoint, true)))
+        Ok(codepoint)
+    }
+}
+
+impl Encoding for Iso8859Decode {
+    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
+        utils::decode_single_bytes(input, |byte| self.decode_byte(byte))
     }
 
     fn replacement(&self) -> Vec<u8> {
@@ -0,0 +1,170 @@
+//! Lookups in the JIS X 0208 and JIS X 0212 character sets, and the half-width katakana of
+//! JIS X 0201, shared by the Japanese encodings.
+//!
+//! JIS X 0208 and JIS X 0212 each arrange their characters in a grid of 94 rows of 94 cells,
+//! numbered from 1. The tables used are the WHATWG Encoding Standard's indexes, where a
+//! character's pointer is `(row - 1) * 94 + (cell - 1)`.
+
+use encoding_index_japanese::{jis0208, jis0212};
+
+/// The half-width katakana, which are bytes 0xA1 to 0xDF in JIS X 0201.
+pub const HALFWIDTH: [char; 63] = [
+        '｡','｢','｣','､','･','ｦ','ｧ','ｨ','ｩ','ｪ','ｫ','ｬ','ｭ','ｮ','ｯ',
+    'ｰ','ｱ','ｲ','ｳ','ｴ','ｵ','ｶ','ｷ','ｸ','ｹ','ｺ','ｻ','ｼ','ｽ','ｾ','ｿ',
+    'ﾀ','ﾁ','ﾂ','ﾃ','ﾄ','ﾅ','ﾆ','ﾇ','ﾈ','ﾉ','ﾊ','ﾋ','ﾌ','ﾍ','ﾎ','ﾏ',
+    'ﾐ','ﾑ','ﾒ','ﾓ','ﾔ','ﾕ','ﾖ','ﾗ','ﾘ','ﾙ','ﾚ','ﾛ','ﾜ','ﾝ','ﾞ','ﾟ'
+];
+
+/// The number of cells in each row, and the number of rows.
+pub const ROW_SIZE: u8 = 94;
+
+/// Whether a row is one that JIS X 0208 itself assigns characters in. The index also has the NEC
+/// special characters in row 13 and IBM extensions from row 89 onwards, which were added by
+/// Microsoft.
+pub fn is_jis0208_row(row: u8) -> bool {
+    (1 ..= 8).contains(&row) || (16 ..= 84).contains(&row)
+}
+
+/// The row of the NEC special characters (circled numbers, Roman numerals, units and so on).
+pub const NEC_ROW: u8 = 13;
+
+/// The pointers of the user-defined characters in Windows' code page 932, in rows 95 to 114,
+/// which map to the start of the Private Use Area.
+const USER_DEFINED: std::ops::RangeInclusive<u16> = 8836 ..= 10715;
+const USER_DEFINED_START: u32 = 0xE000;
+
+fn pointer(row: u8, cell: u8) -> u16 {
+    u16::from(row - 1) * u16::from(ROW_SIZE) + u16::from(cell - 1)
+}
+
+fn row_cell(pointer: u16) -> (u8, u8) {
+    ((pointer / u16::from(ROW_SIZE)) as u8 + 1, (pointer % u16::from(ROW_SIZE)) as u8 + 1)
+}
+
+/// Look up the code point at the given row and cell of JIS X 0208. The characters the index has
+/// in Microsoft's forms are given in JIS X 0208's own forms instead.
+pub fn jis0208_decode(row: u8, cell: u8) -> Option<u32> {
+    if !is_jis0208_row(row) || !(1 ..= ROW_SIZE).contains(&cell) {
+        return None;
+    }
+    match jis0208::forward(pointer(row, cell)) {
+        0xFFFF => None,
+        codepoint => Some(jis_form(codepoint).unwrap_or(codepoint)),
+    }
+}
+
+/// Look up the code point at the given row and cell of JIS X 0208, or of the NEC special
+/// characters in row 13, which EUC-JP has as well.
+pub fn jis0208_nec_decode(row: u8, cell: u8) -> Option<u32> {
+    if row != NEC_ROW || !(1 ..= ROW_SIZE).contains(&cell) {
+        return jis0208_decode(row, cell);
+    }
+    match jis0208::forward(pointer(row, cell)) {
+        0xFFFF => None,
+        codepoint => Some(codepoint),
+    }
+}
+
+/// Characters of JIS X 0208 that the index, following Microsoft, maps to different code points,
+/// and what it maps them to instead.
+const MICROSOFT_FORMS: [(u32, u32); 6] = [
+    (0x2212, 0xFF0D), // MINUS SIGN: FULLWIDTH HYPHEN-MINUS
+    (0x301C, 0xFF5E), // WAVE DASH: FULLWIDTH TILDE
+    (0x2016, 0x2225), // DOUBLE VERTICAL LINE: PARALLEL TO
+    (0x00A2, 0xFFE0), // CENT SIGN: FULLWIDTH CENT SIGN
+    (0x00A3, 0xFFE1), // POUND SIGN: FULLWIDTH POUND SIGN
+    (0x00AC, 0xFFE2), // NOT SIGN: FULLWIDTH NOT SIGN
+];
+
+/// The code point the index has for a character of JIS X 0208, if it differs.
+fn microsoft_form(codepoint: u32) -> Option<u32> {
+    MICROSOFT_FORMS.iter().find(|&&(jis, _)| jis == codepoint).map(|&(_, microsoft)| microsoft)
+}
+
+/// The code point JIS X 0208 itself has for a character the index has in Microsoft's form.
+fn jis_form(codepoint: u32) -> Option<u32> {
+    MICROSOFT_FORMS.iter().find(|&&(_, microsoft)| microsoft == codepoint).map(|&(jis, _)| jis)
+}
+
+/// Find the row and cell of a code point in JIS X 0208. The characters the index has in
+/// Microsoft's forms are also found in their original forms.
+pub fn jis0208_encode(codepoint: u32) -> Option<(u8, u8)> {
+    let codepoint = microsoft_form(codepoint).unwrap_or(codepoint);
+    match jis0208::backward(codepoint) {
+        0xFFFF => None,
+        pointer => Some(row_cell(pointer)).filter(|&(row, _)| is_jis0208_row(row)),
+    }
+}
+
+/// Find the row and cell of a code point in JIS X 0208, or failing that, in the NEC special
+/// characters in row 13.
+pub fn jis0208_nec_encode(codepoint: u32) -> Option<(u8, u8)> {
+    jis0208_encode(codepoint).or_else(|| match jis0208::backward(codepoint) {
+        0xFFFF => None,
+        pointer => Some(row_cell(pointer)).filter(|&(row, _)| row == NEC_ROW),
+    })
+}
+
+/// Look up the code point at the given row and cell of JIS X 0208 as extended by Microsoft for
+/// code page 932, which has rows beyond 94 for user-defined characters and IBM extensions.
+pub fn cp932_decode(row: u8, cell: u8) -> Option<u32> {
+    if row == 0 || !(1 ..= ROW_SIZE).contains(&cell) {
+        return None;
+    }
+    let pointer = pointer(row, cell);
+    if USER_DEFINED.contains(&pointer) {
+        return Some(USER_DEFINED_START + u32::from(pointer - USER_DEFINED.start()));
+    }
+    match jis0208::forward(pointer) {
+        0xFFFF => None,
+        codepoint => Some(codepoint),
+    }
+}
+
+/// Find the row and cell of a code point in code page 932's extended JIS X 0208. Where
+/// characters are in both the IBM extensions and NEC's selection of them (rows 89 to 92), the
+/// IBM extensions are used. As with `jis0208_encode`, characters are also found in the forms
+/// JIS X 0208 itself has.
+pub fn cp932_encode(codepoint: u32) -> Option<(u8, u8)> {
+    let codepoint = microsoft_form(codepoint).unwrap_or(codepoint);
+    let user_defined_end = USER_DEFINED_START + u32::from(USER_DEFINED.end() - USER_DEFINED.start());
+    if (USER_DEFINED_START ..= user_defined_end).contains(&codepoint) {
+        return Some(row_cell(USER_DEFINED.start() + (codepoint - USER_DEFINED_START) as u16));
+    }
+    match jis0208::backward_remapped(codepoint) {
+        0xFFFF => None,
+        pointer => Some(row_cell(pointer)),
+    }
+}
+
+/// Look up the code point at the given row and cell of JIS X 0212.
+pub fn jis0212_decode(row: u8, cell: u8) -> Option<u32> {
+    if !(1 ..= ROW_SIZE).contains(&row) || !(1 ..= ROW_SIZE).contains(&cell) {
+        return None;
+    }
+    match jis0212::forward(pointer(row, cell)) {
+        0xFFFF => None,
+        codepoint => Some(codepoint),
+    }
+}
+
+/// Find the row and cell of a code point in JIS X 0212.
+pub fn jis0212_encode(codepoint: u32) -> Option<(u8, u8)> {
+    match jis0212::backward(codepoint) {
+        0xFFFF => None,
+        pointer => Some(row_cell(pointer)),
+    }
+}
+
+#[test]
+fn test_microsoft_forms() {
+    for &(jis, microsoft) in &MICROSOFT_FORMS {
+        let found = jis0208_encode(microsoft);
+        assert!(found.is_some(), "U+{:04X} isn't in the index", microsoft);
+        assert_eq!(found, jis0208_encode(jis));
+        assert_eq!(found, cp932_encode(jis));
+        let (row, cell) = found.unwrap();
+        assert_eq!(Some(jis), jis0208_decode(row, cell));
+        assert_eq!(Some(microsoft), cp932_decode(row, cell));
+    }
+}
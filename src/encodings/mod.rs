@@ -1,17 +1,32 @@
 use super::encoding::*;
 
+mod auto;
+pub use self::auto::*;
+
 mod base64;
 pub use self::base64::*;
 
 mod cp437;
 pub use self::cp437::*;
 
+mod declared;
+pub use self::declared::*;
+
+mod euc_jp;
+pub use self::euc_jp::*;
+
 mod hex;
 pub use self::hex::*;
 
+mod iso2022jp;
+pub use self::iso2022jp::*;
+
 mod iso8859;
 pub use self::iso8859::*;
 
+mod mojibake;
+pub use self::mojibake::*;
+
 mod normalize;
 pub use self::normalize::*;
 
@@ -30,6 +45,9 @@ pub use self::unicode_info::*;
 mod utf16;
 pub use self::utf16::*;
 
+mod utf32;
+pub use self::utf32::*;
+
 mod utf7;
 pub use self::utf7::*;
 
@@ -39,47 +57,68 @@ pub use self::utf8::*;
 mod windows1252;
 pub use self::windows1252::*;
 
+mod jis;
 mod utils;
 
+/// Signature of a function which makes a new instance of an encoding from an options string.
+pub type NewEncodingFn = dyn Fn(&str) -> Result<Box<dyn Encoding>, String>;
+
+/// The static functions of an encoding, as stored in the registry.
 #[derive(Copy, Clone)]
 pub struct CodeFunctions {
-    pub new: &'static dyn Fn(&str) -> Result<Box<dyn Encoding>, String>,
+    pub new: &'static NewEncodingFn,
     pub print_help: &'static dyn Fn(),
+    /// What kind of data the encoding expects as input.
+    pub input: StreamKind,
+    /// What kind of data the encoding produces.
+    pub output: StreamKind,
 }
 
 macro_rules! entry {
-    ($name:expr => $typename:ident) => {
+    ($name:expr => $typename:ident, $input:ident => $output:ident) => {
         ($name, CodeFunctions {
             new: &$typename::new,
             print_help: &$typename::print_help,
+            input: StreamKind::$input,
+            output: StreamKind::$output,
         })
     }
 }
 
-const MAP: [(&str, CodeFunctions); 23] = [
-    entry!("base64" => Base64Encode),
-    entry!("cp437" => Cp437Encode),
-    entry!("hex" => HexEncode),
-    entry!("iso8859" => Iso8859Encode),
-    entry!("normalize" => Normalize),
-    entry!("null" => Null),
-    entry!("shift_jis" => ShiftJISEncode),
-    entry!("ucode" => UCodeEncode),
-    entry!("unicode_info" => UnicodeInfo),
-    entry!("un_base64" => Base64Decode),
-    entry!("un_cp437" => Cp437Decode),
-    entry!("un_hex" => HexDecode),
-    entry!("un_iso8859" => Iso8859Decode),
-    entry!("un_shift_jis" => ShiftJISDecode),
-    entry!("un_ucode" => UCodeDecode),
-    entry!("un_utf16" => Utf16Decode),
-    entry!("un_utf7" => Utf7Decode),
-    entry!("un_utf8" => Utf8Decode),
-    entry!("un_windows1252" => Windows1252Decode),
-    entry!("utf16" => Utf16Encode),
-    entry!("utf7" => Utf7Encode),
-    entry!("utf8" => Utf8Encode),
-    entry!("windows1252" => Windows1252Encode),
+const MAP: [(&str, CodeFunctions); 33] = [
+    entry!("base64" => Base64Encode, Bytes => Text),
+    entry!("cp437" => Cp437Encode, Chars => Bytes),
+    entry!("detect" => Detect, Bytes => Text),
+    entry!("euc_jp" => EucJpEncode, Chars => Bytes),
+    entry!("fix_mojibake" => FixMojibake, Chars => Chars),
+    entry!("hex" => HexEncode, Bytes => Text),
+    entry!("iso2022jp" => Iso2022JpEncode, Chars => Bytes),
+    entry!("iso8859" => Iso8859Encode, Chars => Bytes),
+    entry!("normalize" => Normalize, Chars => Chars),
+    entry!("null" => Null, Bytes => Bytes),
+    entry!("redeclare" => Redeclare, Chars => Chars),
+    entry!("shift_jis" => ShiftJISEncode, Chars => Bytes),
+    entry!("ucode" => UCodeEncode, Chars => Text),
+    entry!("un_auto" => AutoDecode, Bytes => Chars),
+    entry!("un_base64" => Base64Decode, Text => Bytes),
+    entry!("un_cp437" => Cp437Decode, Bytes => Chars),
+    entry!("un_declared" => DeclaredDecode, Bytes => Chars),
+    entry!("un_euc_jp" => EucJpDecode, Bytes => Chars),
+    entry!("un_hex" => HexDecode, Text => Bytes),
+    entry!("un_iso2022jp" => Iso2022JpDecode, Bytes => Chars),
+    entry!("un_iso8859" => Iso8859Decode, Bytes => Chars),
+    entry!("un_shift_jis" => ShiftJISDecode, Bytes => Chars),
+    entry!("un_ucode" => UCodeDecode, Text => Chars),
+    entry!("un_utf16" => Utf16Decode, Bytes => Chars),
+    entry!("un_utf32" => Utf32Decode, Bytes => Chars),
+    entry!("un_utf7" => Utf7Decode, Bytes => Chars),
+    entry!("un_utf8" => Utf8Decode, Bytes => Chars),
+    entry!("un_windows1252" => Windows1252Decode, Bytes => Chars),
+    entry!("unicode_info" => UnicodeInfo, Chars => Chars),
+    entry!("utf16" => Utf16Encode, Chars => Bytes),
+    entry!("utf7" => Utf7Encode, Chars => Bytes),
+    entry!("utf8" => Utf8Encode, Chars => Bytes),
+    entry!("windows1252" => Windows1252Encode, Chars => Bytes),
 ];
 
 fn map_lookup(name: &str) -> Result<CodeFunctions, String> {
@@ -92,6 +131,13 @@ fn map_lookup(name: &str) -> Result<CodeFunctions, String> {
     Err(format!("unknown coding scheme {:?}", name))
 }
 
+/// Iterate over the names of all the encodings in the registry, in alphabetical order.
+pub fn encoding_names() -> impl Iterator<Item = &'static str> {
+    MAP.iter().map(|pair| pair.0)
+}
+
+/// Make a new instance of the named encoding, with the given options.
+/// Returns an error message if the name is not known or the options are invalid.
 pub fn get_encoding(name: &str, options: &str) -> Result<Box<dyn Encoding>, String> {
     match map_lookup(name) {
         Ok(functions) => (functions.new)(options),
@@ -99,20 +145,40 @@ pub fn get_encoding(name: &str, options: &str) -> Result<Box<dyn Encoding>, Stri
     }
 }
 
+/// Get the kinds of data the named encoding takes as input and produces as output.
+pub fn get_stream_kinds(name: &str) -> Result<(StreamKind, StreamKind), String> {
+    map_lookup(name).map(|functions| (functions.input, functions.output))
+}
+
+fn print_functions_help(functions: &CodeFunctions) {
+    (functions.print_help)();
+    println!("Input: {}; output: {}.", functions.input, functions.output);
+}
+
+/// Print the help text for the named encoding, or return an error if the name is not known.
 pub fn print_help(name: &str) -> Result<(), String> {
     match map_lookup(name) {
         Ok(functions) => {
-            (functions.print_help)();
+            print_functions_help(&functions);
             Ok(())
         },
         Err(e) => Err(e),
     }
 }
 
+/// Print the help text for every encoding in the registry.
 pub fn print_all_help() {
     for pair in &MAP {
         println!("{}:", pair.0);
-        (pair.1.print_help)();
+        print_functions_help(&pair.1);
         println!();
     }
 }
+
+#[test]
+fn test_encoding_names() {
+    let names: Vec<&str> = encoding_names().collect();
+    let mut sorted = names.clone();
+    sorted.sort_unstable();
+    assert_eq!(sorted, names);
+}
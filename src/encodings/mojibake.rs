@@ -0,0 +1,211 @@
+use super::super::encoding::*;
+use super::Windows1252Encode;
+use super::utils;
+
+use std::char;
+use std::convert::TryFrom;
+
+/// The most characters to buffer up before looking for mojibake, if there's no line break first.
+/// The buffer is then cut after its last ASCII character, which can't be part of any mojibake, so
+/// that none is cut in half. Only mojibake in a run of more than this many non-ASCII characters
+/// can be missed.
+const MAX_LINE: usize = 4096;
+
+/// The most times to undo mis-decoding of the same text.
+const MAX_PASSES: usize = 3;
+
+pub struct FixMojibake {
+    line: Vec<u32>,
+}
+
+impl EncodingStatics for FixMojibake {
+    fn new(options: &str) -> Result<Box<dyn Encoding>, String> {
+        if !options.is_empty() {
+            return Err("invalid options".into());
+        }
+        Ok(Box::new(FixMojibake { line: vec![] }))
+    }
+
+    fn print_help() {
+        println!("Repairs mojibake in character data: text which was encoded as UTF-8 but then");
+        println!("decoded as Windows 1252 or Latin-1 (possibly more than once), like \"CafÃ©\" for");
+        println!("\"Café\". Sequences of characters which, encoded as Windows 1252 or Latin-1, make a");
+        println!("valid UTF-8 sequence for a printable character are replaced by that character.");
+        println!("The repairs made are logged in verbose mode.");
+        println!("(no options)");
+    }
+}
+
+/// The byte a character would have come from if it was decoded as Windows 1252, or failing that,
+/// Latin-1, and whether it was Latin-1.
+fn legacy_byte(codepoint: u32) -> Option<(u8, bool)> {
+    if let Some(byte) = Windows1252Encode::encode_codepoint(codepoint) {
+        return Some((byte, false));
+    }
+    // Latin-1 is the first 256 code points, so it covers the C1 controls in the gaps in
+    // Windows 1252.
+    u8::try_from(codepoint).ok().map(|byte| (byte, true))
+}
+
+/// Whether a character decoded from UTF-8 is a likely one to find in text, rather than an
+/// accident of some legitimate characters looking like UTF-8.
+fn is_plausible(c: char) -> bool {
+    let codepoint = c as u32;
+    !c.is_control()
+        && c != char::REPLACEMENT_CHARACTER
+        && !(0xE000 ..= 0xF8FF).contains(&codepoint)
+        && !(0xFDD0 ..= 0xFDEF).contains(&codepoint)
+        && codepoint & 0xFFFE != 0xFFFE
+}
+
+/// How out of place a non-ASCII character looks in text: letters least, then other printable
+/// characters, then control characters.
+fn oddness(codepoint: u32) -> u32 {
+    match char::from_u32(codepoint) {
+        Some(c) if c.is_alphabetic() => 1,
+        Some(c) if !c.is_control() => 2,
+        _ => 4,
+    }
+}
+
+/// Undo one round of mis-decoding. Returns the repaired text, if anything was repaired, and
+/// whether the repairs needed Latin-1.
+fn repair(chars: &[u32]) -> Option<(Vec<u32>, bool)> {
+    let bytes: Vec<Option<(u8, bool)>> = chars.iter()
+        .map(|&c| if c < 0x80 { None } else { legacy_byte(c) })
+        .collect();
+    let is_continuation = |i: usize| matches!(bytes.get(i), Some(Some((0x80 ..= 0xBF, _))));
+
+    let mut out = Vec::with_capacity(chars.len());
+    let mut used_latin1 = false;
+    let mut changed = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let nbytes = match bytes[i] {
+            Some((0xC2 ..= 0xDF, _)) => 2,
+            Some((0xE0 ..= 0xEF, _)) => 3,
+            Some((0xF0 ..= 0xF4, _)) => 4,
+            _ => 0,
+        };
+        if nbytes > 0 && (i + 1 .. i + nbytes).all(is_continuation) {
+            let sequence: Vec<u8> = bytes[i .. i + nbytes].iter().map(|b| b.unwrap().0).collect();
+            // Only repair it if the character it decodes to is more plausible than the ones it's
+            // made of.
+            let original_oddness: u32 = chars[i .. i + nbytes].iter().map(|&c| oddness(c)).sum();
+            let decoded = std::str::from_utf8(&sequence).ok()
+                .and_then(|s| s.chars().next())
+                .filter(|&c| is_plausible(c))
+                .map(|c| c as u32)
+                .filter(|&codepoint| oddness(codepoint) < original_oddness);
+            if let Some(codepoint) = decoded {
+                debug!("{:x?} is UTF-8 for U+{:04X}", sequence, codepoint);
+                used_latin1 |= bytes[i .. i + nbytes].iter().any(|b| b.unwrap().1);
+                out.push(codepoint);
+                changed = true;
+                i += nbytes;
+                continue;
+            }
+        }
+        out.push(chars[i]);
+        i += 1;
+    }
+
+    if changed { Some((out, used_latin1)) } else { None }
+}
+
+fn to_string(chars: &[u32]) -> String {
+    chars.iter().map(|&c| char::from_u32(c).unwrap_or(char::REPLACEMENT_CHARACTER)).collect()
+}
+
+impl FixMojibake {
+    fn fix_line(line: &[u32]) -> Vec<u32> {
+        let mut fixed = line.to_vec();
+        let mut passes = 0;
+        let mut used_latin1 = false;
+        while passes < MAX_PASSES {
+            match repair(&fixed) {
+                Some((repaired, latin1)) => {
+                    fixed = repaired;
+                    used_latin1 |= latin1;
+                    passes += 1;
+                },
+                None => break,
+            }
+        }
+        if passes > 0 {
+            info!("repaired mojibake: {:?} -> {:?}: UTF-8 decoded as {}{}",
+                  to_string(line), to_string(&fixed),
+                  if used_latin1 { "Latin-1" } else { "Windows 1252" },
+                  match passes { 1 => "", 2 => ", twice", _ => ", three times" });
+        }
+        fixed
+    }
+}
+
+impl Encoding for FixMojibake {
+    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
+        // Mojibake doesn't span lines, so work a line at a time.
+        let mut line_ended = false;
+        while !line_ended && self.line.len() < MAX_LINE {
+            let codepoint = match input.get_bytes(4) {
+                Some(Ok(bytes)) => utils::u32_from_bytes(&bytes, true),
+                Some(Err(e)) => { return Some(Err(e)); },
+                None => {
+                    if self.line.is_empty() {
+                        return None;
+                    }
+                    line_ended = true;
+                    continue;
+                },
+            };
+            self.line.push(codepoint);
+            line_ended = codepoint == u32::from(b'\n');
+        }
+
+        // Keep any non-ASCII characters at the end of an overlong line for next time, as they
+        // might be the start of some mojibake.
+        let end = if line_ended {
+            self.line.len()
+        } else {
+            self.line.iter().rposition(|&c| c < 0x80).map_or(self.line.len(), |i| i + 1)
+        };
+        let rest = self.line.split_off(end);
+
+        let mut out = Vec::with_capacity(self.line.len() * 4);
+        for codepoint in Self::fix_line(&self.line) {
+            out.extend_from_slice(&utils::u32_to_bytes(codepoint, true));
+        }
+        self.line = rest;
+        Some(Ok(out))
+    }
+}
+
+#[test]
+fn test_fix_mojibake() {
+    let fix = |text: &str| {
+        let chars = text.chars().flat_map(|c| (c as u32).to_be_bytes()).collect::<Vec<u8>>();
+        let input = Box::new(Some(Ok(chars)).into_iter());
+        let encoder = Encoder::new(input, FixMojibake::new("").unwrap(), "fix_mojibake",
+                                   ErrorPolicy::Halt);
+        let out = encoder.collect::<Result<Vec<_>, _>>().unwrap().concat();
+        to_string(&out.chunks(4).map(|c| utils::u32_from_bytes(c, true)).collect::<Vec<_>>())
+    };
+    assert!(FixMojibake::new("latin1").is_err());
+
+    assert_eq!("Café\n", fix("CafÃ©\n"));
+    assert_eq!("it’s", fix("itâ€™s"));
+    assert_eq!("it’s", fix("itÃ¢â‚¬â„¢s"));
+    // U+008D is a C1 control, which only Latin-1 decodes 0x8D into.
+    assert_eq!("Í and €", fix("Ã\u{8D} and â‚¬"));
+    // Legitimate Latin-1 text is left alone.
+    assert_eq!("Ångström, naïve, Ã", fix("Ångström, naïve, Ã"));
+    // Not even when it's valid UTF-8, if what it decodes to is no more likely than what's there:
+    // here, a combining ring above (U+030A) instead of two letters.
+    assert_eq!("XÌŠ", fix("XÌŠ"));
+
+    // Mojibake which straddles the end of the buffer is still found.
+    let long = "x".repeat(MAX_LINE - 1);
+    assert_eq!(format!("{}é and é", long), fix(&format!("{}Ã© and Ã©", long)));
+    let long = "x".repeat(MAX_LINE - 2);
+    assert_eq!(format!("{}’", long), fix(&format!("{}â€™", long)));
+}
@@ -11,6 +11,7 @@ pub struct Normalize {
 }
 
 #[derive(Debug, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 enum NormalizationForm {
     NFD,
     NFKD,
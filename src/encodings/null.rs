@@ -15,13 +15,15 @@ impl EncodingStatics for Null {
 
 impl Encoding for Null {
     fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
-        while let Some(result) = input.get_byte() {
-            if let Err(e) = result {
-                return Some(Err(e));
-            }
-            // else: do nothing
+        loop {
+            let n = match input.fill_buf() {
+                Ok([]) => { return None; },
+                Ok(buf) => buf.len(),
+                Err(e) => { return Some(Err(e)); },
+            };
+            // Discard it.
+            input.consume(n);
         }
-        None
     }
 
     fn replacement(&self) -> Vec<u8> {
@@ -1,47 +1,152 @@
 use super::super::encoding::*;
+use super::jis;
 use super::utils;
 
-//use encoding_index_japanese;
+const REPLACEMENT: u8 = b'?';
 
-const HALFWIDTH: [char; 63] = [
-        '｡','｢','｣','､','･','ｦ','ｧ','ｨ','ｩ','ｪ','ｫ','ｬ','ｭ','ｮ','ｯ',
-    'ｰ','ｱ','ｲ','ｳ','ｴ','ｵ','ｶ','ｷ','ｸ','ｹ','ｺ','ｻ','ｼ','ｽ','ｾ','ｿ',
-    'ﾀ','ﾁ','ﾂ','ﾃ','ﾄ','ﾅ','ﾆ','ﾇ','ﾈ','ﾉ','ﾊ','ﾋ','ﾌ','ﾍ','ﾎ','ﾏ',
-    'ﾐ','ﾑ','ﾒ','ﾓ','ﾔ','ﾕ','ﾖ','ﾗ','ﾘ','ﾙ','ﾚ','ﾛ','ﾜ','ﾝ','ﾞ','ﾟ'
+/// Code page 932's single bytes outside of ASCII and half-width katakana, and what they map to.
+const CP932_SINGLE_BYTES: [(u8, u32); 5] = [
+    (0x80, 0x80),
+    (0xA0, 0xF8F0),
+    (0xFD, 0xF8F1),
+    (0xFE, 0xF8F2),
+    (0xFF, 0xF8F3),
 ];
 
-pub struct ShiftJISEncode;
+/// Parse the options for either direction: returns whether it's code page 932.
+fn parse_options(options: &str) -> Result<bool, String> {
+    match options {
+        "" => Ok(false),
+        "cp932" => Ok(true),
+        _ => Err("invalid options".into()),
+    }
+}
+
+pub struct ShiftJISEncode {
+    cp932: bool,
+}
 
 impl EncodingStatics for ShiftJISEncode {
-    fn new(_options: &str) -> Result<Box<dyn Encoding>, String> {
-        Err("Shift JIS encoding is not implemented yet.".to_owned())
+    fn new(options: &str) -> Result<Box<dyn Encoding>, String> {
+        Ok(Box::new(ShiftJISEncode { cp932: parse_options(options)? }))
     }
 
     fn print_help() {
-        println!("Encodes character data as Shift JIS (not implemented yet)");
+        println!("Encodes character data as Shift JIS.");
+        println!("Like the decoder, bytes 0x5C and 0x7E are the yen sign and overline of JIS-Roman,");
+        println!("so backslash and tilde can't be encoded (except with the cp932 option).");
+        println!("Un-mapped characters are an error; the replacement character is '?'.");
+        println!("Options:");
+        println!("  cp932 = encode as Microsoft's code page 932 (aka Windows-31J) instead, with");
+        println!("      ASCII backslash and tilde, NEC and IBM extensions, and user-defined");
+        println!("      characters from the Private Use Area");
+    }
+}
+
+impl ShiftJISEncode {
+    fn encode_codepoint(&self, codepoint: u32, out: &mut Vec<u8>) -> Result<(), CodeError> {
+        match codepoint {
+            0xA5 if !self.cp932 => {
+                out.push(0x5C); // YEN SIGN
+                return Ok(());
+            },
+            0x203E if !self.cp932 => {
+                out.push(0x7E); // OVERLINE
+                return Ok(());
+            },
+            0x5C | 0x7E if !self.cp932 => (),
+            0 ..= 0x7F => {
+                out.push(codepoint as u8);
+                return Ok(());
+            },
+            _ => (),
+        }
+
+        if self.cp932 {
+            if let Some(&(byte, _)) = CP932_SINGLE_BYTES.iter().find(|&&(_, c)| c == codepoint) {
+                out.push(byte);
+                return Ok(());
+            }
+        }
+
+        if let Some(idx) = jis::HALFWIDTH.iter().position(|&c| c as u32 == codepoint) {
+            debug!("U+{:04X}: single-byte half-width katakana", codepoint);
+            out.push(0xA1 + idx as u8);
+            return Ok(());
+        }
+
+        let found = if self.cp932 {
+            jis::cp932_encode(codepoint)
+        } else {
+            jis::jis0208_encode(codepoint)
+        };
+        match found {
+            Some((row, cell)) => {
+                debug!("U+{:04X} is JIS X 0208 row {} cell {}", codepoint, row, cell);
+                out.extend_from_slice(&double_byte(row, cell));
+                Ok(())
+            },
+            None => Err(CodeError::new(
+                format!("cannot map Unicode code point U+{:04X} into Shift JIS", codepoint))
+                .with_bytes(utils::u32_to_bytes(codepoint, true))
+                .with_unmappable(codepoint)),
+        }
     }
 }
 
 impl Encoding for ShiftJISEncode {
-    fn next(&mut self, _input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
-        unimplemented!()
+    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
+        utils::encode_codepoints(input, |codepoint, out| self.encode_codepoint(codepoint, out))
     }
 
     fn replacement(&self) -> Vec<u8> {
-        unimplemented!()
+        vec![REPLACEMENT]
+    }
+}
+
+/// Make the double-byte Shift JIS character for a JIS X 0208 row and cell: the reverse of
+/// `row_cell`. Rows beyond 94 (only in code page 932) continue on from first byte 0xF0.
+fn double_byte(row: u8, cell: u8) -> [u8; 2] {
+    let first_byte = (row + 1) / 2 + if row <= 62 { 0x80 } else { 0xC0 };
+    let second_byte = if row % 2 == 0 {
+        cell + 0x9E
+    } else if cell <= 63 {
+        cell + 0x3F
+    } else {
+        cell + 0x40
+    };
+    [first_byte, second_byte]
+}
+
+/// Find the JIS X 0208 row and cell of a double-byte Shift JIS character. Each first byte covers
+/// two rows: second bytes 0x40 to 0x9E (skipping 0x7F) are the odd row, and 0x9F to 0xFC the even.
+fn row_cell(first_byte: u8, second_byte: u8) -> (u8, u8) {
+    let offset = if first_byte < 0xA0 { 0x81 } else { 0xC1 };
+    let odd_row = (first_byte - offset) * 2 + 1;
+    match second_byte {
+        0x40 ..= 0x7E => (odd_row, second_byte - 0x3F),
+        0x80 ..= 0x9E => (odd_row, second_byte - 0x40),
+        _ => (odd_row + 1, second_byte - 0x9E),
     }
 }
 
-pub struct ShiftJISDecode;
+pub struct ShiftJISDecode {
+    cp932: bool,
+}
 
 impl EncodingStatics for ShiftJISDecode {
-    fn new(_options: &str) -> Result<Box<dyn Encoding>, String> {
-        Ok(Box::new(ShiftJISDecode))
+    fn new(options: &str) -> Result<Box<dyn Encoding>, String> {
+        Ok(Box::new(ShiftJISDecode { cp932: parse_options(options)? }))
     }
 
     fn print_help() {
         println!("Decodes Shift JIS into character data.");
-        println!("(no options)");
+        println!("Bytes 0x5C and 0x7E are the yen sign and overline of JIS-Roman (except with the");
+        println!("cp932 option).");
+        println!("Options:");
+        println!("  cp932 = decode Microsoft's code page 932 (aka Windows-31J) instead, with ASCII");
+        println!("      backslash and tilde, NEC and IBM extensions, and user-defined characters");
+        println!("      (decoded into the Private Use Area)");
     }
 }
 
@@ -58,23 +163,33 @@ impl Encoding for ShiftJISDecode {
 
         #[allow(clippy::match_overlapping_arm)]
         match first_byte {
-            0x5C => {
+            0x5C if !self.cp932 => {
                 return Some(Ok(vec![0x00, 0x00, 0x00, 0xA5])); // YEN SIGN
             },
-            0x7E => {
+            0x7E if !self.cp932 => {
                 return Some(Ok(vec![0x00, 0x00, 0x20, 0x3E])); // OVERLINE
             },
             0 ..= 0x7F => {
                 debug!("{:#02x}: ASCII {}", first_byte, first_byte as char);
                 return Some(Ok(vec![0x00, 0x00, 0x00, first_byte])); // un-altered ASCII
             },
+            0x80 | 0xA0 | 0xFD ..= 0xFF if self.cp932 => {
+                let &(_, codepoint) = CP932_SINGLE_BYTES.iter()
+                    .find(|&&(byte, _)| byte == first_byte)
+                    .unwrap();
+                return Some(Ok(utils::u32_to_bytes(codepoint, true)));
+            },
+            0xF0 ..= 0xFC if self.cp932 => {
+                // First byte of a user-defined or IBM extension double-byte character
+                debug!("{:#x}: first byte of double-byte sequence", first_byte);
+            },
             0x80 | 0xA0 | 0xF0 ..= 0xFF => {
                 return Some(Err(CodeError::new("illegal first Shift JIS byte").with_bytes(bytes)));
             },
             0xA1 ..= 0xDF => {
                 // Single-byte half-width katakana
                 debug!("{:#02x}: single-byte half-width katakana", first_byte);
-                return Some(Ok(utils::u32_to_bytes(HALFWIDTH[(first_byte - 0xA1) as usize] as u32, true)));
+                return Some(Ok(utils::u32_to_bytes(jis::HALFWIDTH[(first_byte - 0xA1) as usize] as u32, true)));
             },
             0x81 ..= 0x9F | 0xE0 ..= 0xEF => {
                 // First byte of a double-byte JIS X 0208 character
@@ -98,61 +213,200 @@ impl Encoding for ShiftJISDecode {
         };
         bytes.push(second_byte);
 
-        match second_byte {
-            0x00 ..= 0x3F | 0x7F | 0xFD ..= 0xFF => {
-                let err = CodeError::new("illegal second byte of double-byte Shift JIS character")
-                    .with_bytes(bytes);
-                return Some(Err(err));
-            },
-            0x40 ..= 0x9E if first_byte % 2 == 0 => {
-                let err = CodeError::new("mismatching second byte of double-byte Shift JIS character where first byte is even")
-                    .with_bytes(bytes);
-                return Some(Err(err));
-            },
-            0x9F ..= 0xFC if first_byte % 2 == 1 => {
-                let err = CodeError::new("mismatching second byte of double-byte Shift JIS character where first byte is odd")
-                    .with_bytes(bytes);
-                return Some(Err(err));
+        if !(0x40 ..= 0x7E).contains(&second_byte) && !(0x80 ..= 0xFC).contains(&second_byte) {
+            if second_byte < 0x80 {
+                // Leave ASCII to be decoded as itself, and not as part of the error.
+                input.unget_byte(second_byte);
+                bytes.pop();
             }
-            _ => ()
-        };
+            let err = CodeError::new("illegal second byte of double-byte Shift JIS character")
+                .with_bytes(bytes);
+            return Some(Err(err));
+        }
 
         debug!("{:#02x} {:#02x}", first_byte, second_byte);
 
-        let mut j1 = match first_byte {
-            0x81 ..= 0x9F => {
-                (first_byte - 0x70) * 2
-            },
-            0xE0 ..= 0xEF => {
-                (first_byte - 0xB0) * 2
-            },
-            _ => unreachable!()
-        };
-        
-        let j2 = if second_byte < 0x9F {
-            j1 -= 1;
-            assert!(j1 % 2 == 1);
-            if second_byte > 0x7E {
-                second_byte - 0x20
-            } else {
-                second_byte - 0x1F
-            }
+        let (row, cell) = row_cell(first_byte, second_byte);
+        debug!("JIS X 0208 row {} cell {}", row, cell);
+
+        let found = if self.cp932 {
+            jis::cp932_decode(row, cell)
         } else {
-            second_byte - 0x7E
+            jis::jis0208_decode(row, cell)
         };
+        match found {
+            Some(codepoint) => Some(Ok(utils::u32_to_bytes(codepoint, true))),
+            None => {
+                if second_byte < 0x80 {
+                    // As above, an ASCII byte gets decoded as itself next.
+                    input.unget_byte(second_byte);
+                    bytes.pop();
+                }
+                let err = CodeError::new(
+                    format!("no character at JIS X 0208 row {} cell {}", row, cell))
+                    .with_bytes(bytes);
+                Some(Err(err))
+            },
+        }
+    }
 
-        debug!("JIS sequence {:#02x} {:#02x}", j1, j2);
+    fn replacement(&self) -> Vec<u8> {
+        utils::unicode_replacement()
+    }
+}
 
-        // Now we have a JIS 0208 codepoint, how do we get a Unicode code point?
+#[test]
+fn test_shift_jis_decode() {
+    let decode = |input: &[u8]| {
+        let chunks = Box::new(Some(Ok(input.to_vec())).into_iter());
+        let encoder = Encoder::new(chunks, ShiftJISDecode::new("").unwrap(), "un_shift_jis",
+                                   ErrorPolicy::Halt);
+        encoder.collect::<Result<Vec<_>, _>>()
+            .map(|chunks| chunks.concat().chunks(4)
+                 .map(|c| std::char::from_u32(utils::u32_from_bytes(c, true)).unwrap())
+                 .collect::<String>())
+    };
 
-        //let codepoint: u16 = (j1 as u16) << 8 | (j2 as u16);
-        //let unicode: u32 = encoding_index_japanese::jis0208::backward(codepoint as u32) as u32;
-        //Some(Ok(utils::u32_to_bytes(unicode, true)))
+    assert_eq!("\u{3000}\u{4E9C}a\u{A5}\u{FF71}", decode(b"\x81\x40\x88\x9Fa\x5C\xB1").unwrap());
 
-        Some(Ok(self.replacement()))
+    // The last character in every row.
+    let last_in_row = [
+        (b"\x81\x9E", '\u{25C7}'), (b"\x81\xFC", '\u{25EF}'), (b"\x82\x9A", '\u{FF5A}'),
+        (b"\x82\xF1", '\u{3093}'), (b"\x83\x96", '\u{30F6}'), (b"\x83\xD6", '\u{3C9}'),
+        (b"\x84\x91", '\u{44F}'), (b"\x84\xBE", '\u{2542}'), (b"\x88\xFC", '\u{852D}'),
+        (b"\x89\x9E", '\u{5FDC}'), (b"\x89\xFC", '\u{6539}'), (b"\x8A\x9E", '\u{8431}'),
+        (b"\x8A\xFC", '\u{68C4}'), (b"\x8B\x9E", '\u{4EAC}'), (b"\x8B\xFC", '\u{5C48}'),
+        (b"\x8C\x9E", '\u{6372}'), (b"\x8C\xFC", '\u{5411}'), (b"\x8D\x9E", '\u{8FBC}'),
+        (b"\x8D\xFC", '\u{5237}'), (b"\x8E\x9E", '\u{6642}'), (b"\x8E\xFC", '\u{5468}'),
+        (b"\x8F\x9E", '\u{511F}'), (b"\x8F\xFC", '\u{98FE}'), (b"\x90\x9E", '\u{88FE}'),
+        (b"\x90\xFC", '\u{7DDA}'), (b"\x91\x9E", '\u{618E}'), (b"\x91\xFC", '\u{53EA}'),
+        (b"\x92\x9E", '\u{5BF5}'), (b"\x92\xFC", '\u{9013}'), (b"\x93\x9E", '\u{5230}'),
+        (b"\x93\xFC", '\u{5165}'), (b"\x94\x9E", '\u{9EA6}'), (b"\x94\xFC", '\u{7F8E}'),
+        (b"\x95\x9E", '\u{670D}'), (b"\x95\xFC", '\u{670B}'), (b"\x96\x9E", '\u{6E80}'),
+        (b"\x96\xFC", '\u{7652}'), (b"\x97\x9E", '\u{7483}'), (b"\x97\xFC", '\u{806F}'),
+        (b"\x98\x72", '\u{8155}'), (b"\x98\xFC", '\u{50B2}'), (b"\x99\x9E", '\u{8FA8}'),
+        (b"\x99\xFC", '\u{54A8}'), (b"\x9A\x9E", '\u{5709}'), (b"\x9A\xFC", '\u{5969}'),
+        (b"\x9B\x9E", '\u{5C53}'), (b"\x9B\xFC", '\u{5ECF}'), (b"\x9C\x9E", '\u{609A}'),
+        (b"\x9C\xFC", '\u{621B}'), (b"\x9D\x9E", '\u{64BC}'), (b"\x9D\xFC", '\u{66BC}'),
+        (b"\x9E\x9E", '\u{68CD}'), (b"\x9E\xFC", '\u{6AA3}'), (b"\x9F\x9E", '\u{6CBE}'),
+        (b"\x9F\xFC", '\u{6ECC}'), (b"\xE0\x9E", '\u{71FC}'), (b"\xE0\xFC", '\u{73F1}'),
+        (b"\xE1\x9E", '\u{7670}'), (b"\xE1\xFC", '\u{78EC}'), (b"\xE2\x9E", '\u{7B50}'),
+        (b"\xE2\xFC", '\u{7D06}'), (b"\xE3\x9E", '\u{7F3A}'), (b"\xE3\xFC", '\u{814B}'),
+        (b"\xE4\x9E", '\u{82D9}'), (b"\xE4\xFC", '\u{8548}'), (b"\xE5\x9E", '\u{8759}'),
+        (b"\xE5\xFC", '\u{895E}'), (b"\xE6\x9E", '\u{8B6B}'), (b"\xE6\xFC", '\u{8E4A}'),
+        (b"\xE7\x9E", '\u{8FF8}'), (b"\xE7\xFC", '\u{932E}'), (b"\xE8\x9E", '\u{965E}'),
+        (b"\xE8\xFC", '\u{9870}'), (b"\xE9\x9E", '\u{9AF7}'), (b"\xE9\xFC", '\u{9D48}'),
+        (b"\xEA\x9E", '\u{9FA0}'), (b"\xEA\xA4", '\u{7199}'),
+    ];
+    for &(bytes, expected) in &last_in_row {
+        assert_eq!(expected.to_string(), decode(bytes).unwrap(), "decoding {:x?}", bytes);
     }
 
-    fn replacement(&self) -> Vec<u8> {
-        utils::unicode_replacement()
+    // The characters the index has in Microsoft's forms decode to JIS X 0208's own.
+    let jis_forms = [
+        (b"\x81\x60", '\u{301C}'), (b"\x81\x61", '\u{2016}'), (b"\x81\x7C", '\u{2212}'),
+        (b"\x81\x91", '\u{A2}'), (b"\x81\x92", '\u{A3}'), (b"\x81\xCA", '\u{AC}'),
+    ];
+    for &(bytes, expected) in &jis_forms {
+        assert_eq!(expected.to_string(), decode(bytes).unwrap(), "decoding {:x?}", bytes);
+    }
+
+    // Every double-byte character the table has decodes, and nothing in the rows JIS X 0208
+    // doesn't use decodes.
+    for first_byte in (0x81 ..= 0x9F).chain(0xE0 ..= 0xEF) {
+        for second_byte in (0x40 ..= 0x7E).chain(0x80 ..= 0xFC) {
+            let (row, cell) = row_cell(first_byte, second_byte);
+            let result = decode(&[first_byte, second_byte]);
+            if !jis::is_jis0208_row(row) {
+                assert!(result.is_err(), "{:x} {:x} in row {}", first_byte, second_byte, row);
+            } else if jis::jis0208_decode(row, cell).is_some() {
+                assert!(result.is_ok(), "{:x} {:x} in row {}: {}", first_byte, second_byte, row,
+                        result.unwrap_err());
+            }
+        }
+    }
+
+    // A bad second byte which is ASCII isn't swallowed.
+    let chunks = Box::new(Some(Ok(b"\x88A".to_vec())).into_iter());
+    let encoder = Encoder::new(chunks, ShiftJISDecode::new("").unwrap(), "un_shift_jis",
+                               ErrorPolicy::Replace);
+    let out = encoder.collect::<Result<Vec<_>, _>>().unwrap().concat();
+    assert_eq!(vec![0, 0, 0xFF, 0xFD, 0, 0, 0, b'A'], out);
+    // Nor is it part of the error.
+    let e = decode(b"\x88 ").unwrap_err();
+    assert_eq!(Some(&b"\x88"[..]), e.bad_bytes());
+}
+
+#[test]
+fn test_shift_jis_encode() {
+    let encode = |text: &str| {
+        let chars = text.chars().flat_map(|c| (c as u32).to_be_bytes()).collect::<Vec<u8>>();
+        let encoder = Encoder::new(Box::new(Some(Ok(chars)).into_iter()),
+                                   ShiftJISEncode::new("").unwrap(), "shift_jis",
+                                   ErrorPolicy::Halt);
+        encoder.collect::<Result<Vec<_>, _>>().map(|chunks| chunks.concat())
+    };
+    assert_eq!(b"\x93\xFA\x96\x7B\x8C\xEA a\x5C\x7E\xB1\xDF".to_vec(),
+               encode("\u{65E5}\u{672C}\u{8A9E} a\u{A5}\u{203E}\u{FF71}\u{FF9F}").unwrap());
+    let e = encode("C:\\").unwrap_err();
+    assert_eq!(Some(0x5C), e.unmappable());
+    assert!(encode("\u{00E9}").is_err());
+    // Characters are encoded from JIS X 0208's own forms as well as Microsoft's.
+    assert_eq!(encode("\u{FF5E}\u{FF0D}\u{FFE0}").unwrap(),
+               encode("\u{301C}\u{2212}\u{A2}").unwrap());
+
+    // Everything that decodes encodes back to the same bytes.
+    for row in 1 ..= jis::ROW_SIZE {
+        for cell in 1 ..= jis::ROW_SIZE {
+            let bytes = double_byte(row, cell);
+            assert_eq!((row, cell), row_cell(bytes[0], bytes[1]));
+            if let Some(codepoint) = jis::jis0208_decode(row, cell) {
+                let c = std::char::from_u32(codepoint).unwrap();
+                assert_eq!(bytes.to_vec(), encode(&c.to_string()).unwrap(), "encoding {:?}", c);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_cp932() {
+    let convert = |encoding: Box<dyn Encoding>, input: Vec<u8>| {
+        let encoder = Encoder::new(Box::new(Some(Ok(input)).into_iter()), encoding, "shift_jis",
+                                   ErrorPolicy::Halt);
+        encoder.collect::<Result<Vec<_>, _>>().map(|chunks| chunks.concat())
+    };
+    let decode = |input: &[u8]| {
+        convert(ShiftJISDecode::new("cp932").unwrap(), input.to_vec()).map(|chars| {
+            chars.chunks(4)
+                .map(|c| std::char::from_u32(utils::u32_from_bytes(c, true)).unwrap())
+                .collect::<String>()
+        })
+    };
+    let encode = |text: &str| {
+        let chars = text.chars().flat_map(|c| (c as u32).to_be_bytes()).collect::<Vec<u8>>();
+        convert(ShiftJISEncode::new("cp932").unwrap(), chars)
+    };
+
+    let bytes = b"C:\\~ \x87\x40\xFA\x40\xED\x40\xF0\x40\xF9\xFC\x81\x7C\xA0";
+    let text = "C:\\~ \u{2460}\u{2170}\u{7E8A}\u{E000}\u{E757}\u{FF0D}\u{F8F0}";
+    assert_eq!(text, decode(bytes).unwrap());
+    // U+2170 and U+7E8A are also among the NEC-selected IBM extensions, but encode to the IBM
+    // extensions.
+    assert_eq!(b"C:\\~ \x87\x40\xFA\x40\xFA\x5C\xF0\x40\xF9\xFC\x81\x7C\xA0".to_vec(),
+               encode(text).unwrap());
+    assert_eq!(b"\x81\x7C".to_vec(), encode("\u{2212}").unwrap());
+    assert_eq!(Some(0xA5), encode("\u{A5}").unwrap_err().unmappable());
+
+    // Plain Shift JIS has none of the extensions.
+    let plain = ShiftJISDecode::new("").unwrap();
+    assert!(convert(plain, b"\x87\x40".to_vec()).is_err());
+
+    // Everything that decodes encodes back to something that decodes the same.
+    for first_byte in (0x81 ..= 0x9F).chain(0xE0 ..= 0xFC) {
+        for second_byte in (0x40 ..= 0x7E).chain(0x80 ..= 0xFC) {
+            if let Ok(decoded) = decode(&[first_byte, second_byte]) {
+                assert_eq!(decoded, decode(&encode(&decoded).unwrap()).unwrap());
+            }
+        }
     }
 }
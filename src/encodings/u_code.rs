@@ -18,7 +18,7 @@ impl EncodingStatics for UCodeDecode {
 }
 
 fn hex_digit_value(c: u8) -> Option<u8> {
-    if (b'0'..=b'9').contains(&c) {
+    if c.is_ascii_digit() {
         Some(c - b'0')
     } else if (b'a'..=b'f').contains(&c) {
         Some(c - b'a' + 10)
@@ -69,14 +69,11 @@ impl Utf16Encode {
 
 impl Encoding for Utf16Encode {
     fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
-        match input.get_bytes(4) {
-            Some(Ok(bytes)) => {
-                let codepoint = utils::u32_from_bytes(&bytes, true);
-                Some(Self::encode_codepoint(codepoint, self.big_endian))
-            },
-            Some(Err(e)) => Some(Err(e)),
-            None => None,
-        }
+        let big_endian = self.big_endian;
+        utils::encode_codepoints(input, |codepoint, out| {
+            out.extend(Self::encode_codepoint(codepoint, big_endian)?);
+            Ok(())
+        })
     }
 
     fn replacement(&self) -> Vec<u8> {
@@ -0,0 +1,117 @@
+use super::super::encoding::*;
+use super::utils;
+
+pub struct Utf32Decode {
+    big_endian: bool,
+}
+
+impl EncodingStatics for Utf32Decode {
+    fn new(options: &str) -> Result<Box<dyn Encoding>, String> {
+        let mut big_endian = false;
+        match options {
+            "" | "le" => (),
+            "be" => { big_endian = true; },
+            _ =>  { return Err("invalid options".into()); },
+        }
+
+        Ok(Box::new(Utf32Decode { big_endian }))
+    }
+
+    fn print_help() {
+        println!("Decodes UTF-32 input into character data (UTF-32BE)");
+        println!("Options:");
+        println!("  le = little endian (UTF-32LE) input (default)");
+        println!("  be = big endian (UTF-32BE) input");
+    }
+}
+
+impl Encoding for Utf32Decode {
+    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
+        let buf = match input.fill_buf() {
+            Ok([]) => { return None; },
+            Ok(buf) => buf,
+            Err(e) => { return Some(Err(e)); },
+        };
+        if buf.len() < 4 {
+            // A code unit split across chunks, or cut off by the end of the input.
+            return match input.get_bytes(4) {
+                Some(Ok(bytes)) => Some(self.decode_codeunit(&bytes)),
+                other => other,
+            };
+        }
+
+        // Decode every whole code unit buffered, up to the first one that isn't a code point.
+        let mut out = Vec::with_capacity(buf.len() - buf.len() % 4);
+        for unit in buf.chunks_exact(4) {
+            match self.decode_codeunit(unit) {
+                Ok(bytes) => out.extend(bytes),
+                Err(e) if out.is_empty() => {
+                    input.consume(4);
+                    return Some(Err(e));
+                },
+                Err(_) => { break; },
+            }
+        }
+        input.consume(out.len());
+        Some(Ok(out))
+    }
+
+    fn replacement(&self) -> Vec<u8> {
+        utils::unicode_replacement()
+    }
+}
+
+impl Utf32Decode {
+    /// Turn one code unit into character data, checking that it is a Unicode scalar value.
+    fn decode_codeunit(&self, bytes: &[u8]) -> Result<Vec<u8>, CodeError> {
+        let codepoint = utils::u32_from_bytes(bytes, self.big_endian);
+        let problem = if codepoint > 0x10FFFF {
+            "code point out of Unicode range"
+        } else if (0xD800..=0xDFFF).contains(&codepoint) {
+            "surrogate code point is illegal in UTF-32"
+        } else {
+            return Ok(utils::u32_to_bytes(codepoint, true));
+        };
+        error!("{}: U+{:X}", problem, codepoint);
+        Err(CodeError::new(problem).with_bytes(bytes.to_vec()))
+    }
+}
+
+#[test]
+fn test_utf32() {
+    let convert = |options, chunks: Vec<&[u8]>, error_policy| {
+        let input = chunks.into_iter().map(|chunk| Ok(chunk.to_vec())).collect::<Vec<_>>();
+        let encoder = Encoder::new(Box::new(input.into_iter()), Utf32Decode::new(options).unwrap(),
+                                   "un_utf32", error_policy);
+        encoder.collect::<Result<Vec<_>, _>>().map(|chunks| chunks.concat())
+    };
+    let chars = |text: &str| text.chars().flat_map(|c| (c as u32).to_be_bytes()).collect::<Vec<u8>>();
+
+    let le = b"a\0\0\0\xE9\0\0\0\x00\xF6\x01\0";
+    let be = b"\0\0\0a\0\0\0\xE9\0\x01\xF6\x00";
+    assert_eq!(chars("a\u{E9}\u{1F600}"), convert("", vec![le], ErrorPolicy::Halt).unwrap());
+    assert_eq!(chars("a\u{E9}\u{1F600}"), convert("le", vec![le], ErrorPolicy::Halt).unwrap());
+    assert_eq!(chars("a\u{E9}\u{1F600}"), convert("be", vec![be], ErrorPolicy::Halt).unwrap());
+    assert!(Utf32Decode::new("utf16").is_err());
+
+    // A byte order mark isn't treated specially; it's up to un_auto to skip it.
+    assert_eq!(chars("\u{FEFF}a"), convert("be", vec![b"\0\0\xFE\xFF\0\0\0a"], ErrorPolicy::Halt)
+               .unwrap());
+
+    // Code units split across chunks.
+    assert_eq!(chars("a\u{E9}\u{1F600}"),
+               convert("be", vec![&be[..2], &be[2..7], &be[7..]], ErrorPolicy::Halt).unwrap());
+
+    // Code units which aren't Unicode scalar values.
+    let e = convert("be", vec![b"\0\0\0a\0\x11\0\0"], ErrorPolicy::Halt).unwrap_err();
+    assert_eq!(Some(&b"\0\x11\0\0"[..]), e.bad_bytes());
+    let e = convert("le", vec![b"\0\xD8\0\0"], ErrorPolicy::Halt).unwrap_err();
+    assert_eq!(Some(&b"\0\xD8\0\0"[..]), e.bad_bytes());
+    let bad = b"\0\0\0a\xFF\xFF\xFF\xFF\0\0\xDC\0\0\0\0b";
+    assert_eq!(chars("a\u{FFFD}\u{FFFD}b"), convert("be", vec![bad], ErrorPolicy::Replace).unwrap());
+
+    // Input which ends in the middle of a code unit.
+    assert!(convert("be", vec![b"\0\0\0a\0\0"], ErrorPolicy::Halt).is_err());
+    assert_eq!(chars("a\u{FFFD}"),
+               convert("be", vec![b"\0\0\0a\0\0"], ErrorPolicy::Replace).unwrap());
+}
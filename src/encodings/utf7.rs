@@ -60,7 +60,15 @@ impl Encoding for Utf7Encode {
                     bytes = bytes_read;
                     utils::u32_from_bytes(&bytes, true)
                 },
-                other => { return other; }
+                Some(Err(e)) => { return Some(Err(e)); },
+                None => {
+                    // Don't lose anything we output before reaching EOF.
+                    return if out.is_empty() {
+                        None
+                    } else {
+                        Some(Ok(out))
+                    };
+                },
             };
 
             debug!("encoding code point U+{:04X}", codepoint);
@@ -103,6 +111,17 @@ impl Encoding for Utf7Encode {
             }
         }
     }
+
+    fn finish(&mut self) -> Result<Vec<u8>, CodeError> {
+        let mut out = vec![];
+        if self.mode == Mode::Unicode {
+            debug!("ending unicode encoding at EOF");
+            self.flush_buffer(&mut out);
+            out.push(b'-');
+            self.mode = Mode::Direct;
+        }
+        Ok(out)
+    }
 }
 
 pub struct Utf7Decode {
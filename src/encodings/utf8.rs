@@ -16,17 +16,9 @@ impl EncodingStatics for Utf8Encode {
     }
 }
 
-impl Encoding for Utf8Encode {
-    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
-        let bytes: Vec<u8>;
-        let codepoint = match input.get_bytes(4) {
-            Some(Ok(bytes_read)) => {
-                bytes = bytes_read;
-                utils::u32_from_bytes(&bytes, true)
-            },
-            other => { return other; }
-        };
-
+impl Utf8Encode {
+    /// Append the UTF-8 encoding of a single code point to the output.
+    pub fn encode_codepoint(codepoint: u32, out: &mut Vec<u8>) -> Result<(), CodeError> {
         debug!("endcoding code point U+{:04X}", codepoint);
 
         // These ranges are illegal in Unicode, but UTF-8 can technically encode them just fine.
@@ -38,7 +30,6 @@ impl Encoding for Utf8Encode {
             warn!("low surrogate code point U+{:X} is illegal in UTF-8", codepoint);
         }
 
-        let mut out = vec![];
         if codepoint < 0x80 {
             debug!("1-byte codepoint");
             out.push(codepoint as u8);
@@ -74,10 +65,16 @@ impl Encoding for Utf8Encode {
             out.push(0b10000000 | ((codepoint & 0b00111111) as u8));
         } else {
             error!("code point out of range: cannot be represented in UTF-8: U+{:X}", codepoint);
-            return Some(Err(CodeError::new("code point out of range: cannot be represented in UTF-8")
-                                      .with_bytes(bytes)));
+            return Err(CodeError::new("code point out of range: cannot be represented in UTF-8")
+                                 .with_bytes(utils::u32_to_bytes(codepoint, true)));
         }
-        Some(Ok(out))
+        Ok(())
+    }
+}
+
+impl Encoding for Utf8Encode {
+    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
+        utils::encode_codepoints(input, Self::encode_codepoint)
     }
 
     fn replacement(&self) -> Vec<u8> {
@@ -85,33 +82,48 @@ impl Encoding for Utf8Encode {
     }
 }
 
-pub struct Utf8Decode;
+pub struct Utf8Decode {
+    strict: bool,
+}
 
 impl EncodingStatics for Utf8Decode {
-    fn new(_options: &str) -> Result<Box<dyn Encoding>, String> {
-        Ok(Box::new(Utf8Decode))
+    fn new(options: &str) -> Result<Box<dyn Encoding>, String> {
+        let strict = match options {
+            "relaxed" | "legacy" => false,
+            "" | "strict" => true,
+            _ => { return Err("invalid options".into()); },
+        };
+        Ok(Box::new(Utf8Decode { strict }))
     }
 
     fn print_help() {
-        // TODO: add a "strict" mode that checks for overlong sequences (or maybe invert that and
-        // have a "relaxed" mode)
-        // TODO: add a mode that yields substitution characters instead of errors
         println!("Decodes UTF-8 input into character data (UTF-32BE)");
-        println!("(no options)");
+        println!("Options:");
+        println!("  strict = only accept well-formed UTF-8 as defined by RFC 3629; anything else");
+        println!("      is an error (default). Each error covers the longest start of a valid");
+        println!("      sequence (or one byte), so errors=replace gives the same U+FFFD characters");
+        println!("      as web browsers and the WHATWG Encoding Standard.");
+        println!("  relaxed = also accept 5- and 6-byte sequences, overlong sequences, encoded");
+        println!("      surrogates and code points above U+10FFFF, with a warning (also called");
+        println!("      legacy).");
     }
 }
 
+/// The error for a sequence which ended early, either because of an error getting the next byte,
+/// or EOF, or the given byte which can't continue it (which isn't included in `bytes`).
 #[allow(clippy::unnecessary_wraps)] // wraps are delicious
-fn incomplete_error(nbytes: u8, bytes: Vec<u8>, error: Option<Box<dyn Error>>)
+fn incomplete_error(nbytes: u8, bytes: Vec<u8>, next_byte: Option<u8>,
+                    error: Option<Box<dyn Error + Send + Sync>>)
         -> Option<Result<Vec<u8>, CodeError>> {
-    let last_byte = *bytes.last().unwrap();
-    let mut msg = format!("incomplete multi-byte code point: expected {} bytes, only got {}", nbytes, bytes.len() - 1);
+    let mut msg = format!("incomplete multi-byte code point: expected {} bytes, only got {}", nbytes, bytes.len());
     if let Some(ref e) = error {
         msg.push_str(&format!(" due to error: {}", e));
-    } else if last_byte < 0b10000000 {
-        msg.push_str(&format!(" due to single-byte codepoint {:#x}", last_byte));
-    } else if last_byte >= 0b11100000 {
-        msg.push_str(&format!(" due to unexpected initial byte {:#x}", last_byte));
+    } else if let Some(byte) = next_byte {
+        if byte < 0b10000000 {
+            msg.push_str(&format!(" due to single-byte codepoint {:#x}", byte));
+        } else {
+            msg.push_str(&format!(" due to unexpected initial byte {:#x}", byte));
+        }
     } else {
         msg.push_str(" due to EOF");
     }
@@ -123,6 +135,22 @@ fn incomplete_error(nbytes: u8, bytes: Vec<u8>, error: Option<Box<dyn Error>>)
 
 impl Encoding for Utf8Decode {
     fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
+        // Fast path: decode any run of ASCII all in one go.
+        match input.fill_buf() {
+            Ok(buf) => {
+                let n = buf.iter().take_while(|&&byte| byte < 0x80).count();
+                if n > 0 {
+                    let mut out = Vec::with_capacity(n * 4);
+                    for &byte in &buf[.. n] {
+                        out.extend_from_slice(&[0, 0, 0, byte]);
+                    }
+                    input.consume(n);
+                    return Some(Ok(out));
+                }
+            },
+            Err(e) => { return Some(Err(e)); },
+        }
+
         let mut bytes = vec![];
 
         let first_byte = match input.get_byte() {
@@ -132,6 +160,20 @@ impl Encoding for Utf8Decode {
         };
         bytes.push(first_byte);
 
+        // A continuation byte can't start a sequence in any form of UTF-8.
+        if (0b10000000..0b11000000).contains(&first_byte) {
+            error!("unexpected continuation byte {:#x}", first_byte);
+            return Some(Err(CodeError::new("unexpected continuation byte").with_bytes(bytes)));
+        }
+        if self.strict {
+            // 0xC0 and 0xC1 can only start overlong sequences, and 0xF5 and up can only start
+            // sequences for code points above U+10FFFF.
+            if first_byte == 0xC0 || first_byte == 0xC1 || first_byte >= 0xF5 {
+                error!("illegal byte {:#x}", first_byte);
+                return Some(Err(CodeError::new("illegal byte").with_bytes(bytes)));
+            }
+        }
+
         let mut codepoint: u32;
         let nbytes: u8;
         if first_byte < 0b10000000 {
@@ -179,15 +221,39 @@ impl Encoding for Utf8Decode {
         for i in 1..nbytes {
             let byte = match input.get_byte() {
                 Some(Ok(byte)) => byte,
-                Some(Err(e)) => { return incomplete_error(nbytes, bytes, Some(Box::new(e))); },
-                None => { return incomplete_error(nbytes, bytes, None); },
+                Some(Err(e)) => {
+                    return incomplete_error(nbytes, bytes, None, Some(Box::new(e)));
+                },
+                None => { return incomplete_error(nbytes, bytes, None, None); },
             };
             bytes.push(byte);
 
-            if !(0b1000_0000..=0b1100_0000).contains(&byte) {
-                // unexpected single-byte or initial byte
+            // In strict mode, the second byte is checked against the range which makes a
+            // well-formed sequence (Table 3-7 in the Unicode Standard), so that an ill-formed
+            // sequence ends at its "maximal subpart", like the WHATWG Encoding Standard requires:
+            // each error is then replaced with one U+FFFD, the same as other decoders do.
+            let allowed = match (self.strict, i, first_byte) {
+                (true, 1, 0xE0) => 0xA0 ..= 0xBF,
+                (true, 1, 0xED) => 0x80 ..= 0x9F,
+                (true, 1, 0xF0) => 0x90 ..= 0xBF,
+                (true, 1, 0xF4) => 0x80 ..= 0x8F,
+                _ => 0x80 ..= 0xBF,
+            };
+            if self.strict && !allowed.contains(&byte) && (0x80 .. 0xC0).contains(&byte) {
                 input.unget_byte(byte);
-                return incomplete_error(nbytes, bytes, None);
+                bytes.pop();
+                let problem = match first_byte {
+                    0xE0 | 0xF0 => "overlong sequence",
+                    0xED => "encoded surrogate",
+                    _ => "code point out of Unicode range",
+                };
+                error!("{}: {:x?} followed by {:#x}", problem, bytes, byte);
+                return Some(Err(CodeError::new(problem).with_bytes(bytes)));
+            } else if !(0b1000_0000..0b1100_0000).contains(&byte) {
+                // unexpected single-byte or initial byte, which is left to be decoded next
+                input.unget_byte(byte);
+                bytes.pop();
+                return incomplete_error(nbytes, bytes, Some(byte), None);
             } else {
                 // continuation byte
                 let shift = 6 * (nbytes - i - 1);
@@ -199,12 +265,24 @@ impl Encoding for Utf8Decode {
 
         debug!("got U+{:04X}", codepoint);
 
-        if (nbytes == 2 && codepoint < 0x80)
+        let problem = if (nbytes == 2 && codepoint < 0x80)
                 || (nbytes == 3 && codepoint < 0x800)
                 || (nbytes == 4 && codepoint < 0x1_0000)
                 || nbytes > 4 {
-            warn!("overlong sequence: {:x?}", bytes);
-            // TODO: raise error here if in strict mode
+            Some("overlong sequence")
+        } else if (0xD800..=0xDFFF).contains(&codepoint) {
+            Some("encoded surrogate")
+        } else if codepoint > 0x10FFFF {
+            Some("code point out of Unicode range")
+        } else {
+            None
+        };
+        if let Some(problem) = problem {
+            if self.strict {
+                error!("{}: {:x?}", problem, bytes);
+                return Some(Err(CodeError::new(problem).with_bytes(bytes)));
+            }
+            warn!("{}: {:x?}", problem, bytes);
         }
 
         Some(Ok(utils::u32_to_bytes(codepoint, true)))
@@ -213,4 +291,76 @@ impl Encoding for Utf8Decode {
     fn replacement(&self) -> Vec<u8> {
         utils::unicode_replacement()
     }
+
+    fn decodes_invalid_input(&self) -> bool {
+        !self.strict
+    }
+}
+
+#[test]
+fn test_utf8_maximal_subparts() {
+    let inputs: [&[u8]; 8] = [
+        b"\x61\xF1\x80\x80\xE1\x80\xC2\x62\x80\x63\x80\xBF\x64",
+        b"\xC0\xAF\xE0\x80\xBF\xF0\x81\x82\x41",
+        b"\xED\xA0\x80\xED\xBF\xBF\xED\xAF\x41",
+        b"\xF4\x91\x92\x93\xFF\x41\x80\xBF\x42",
+        b"\xE1\x80\xE2\xF0\x91\x92\xF1\xBF\x41",
+        b"\xF8\x88\x80\x80\x80\xFC\x84\x80\x80\x80\x80",
+        b"ok \xE2\x82",
+        b"\xF0\x9F\x98\x80 \xF0\x9F\x98",
+    ];
+    for (&input, options) in inputs.iter().flat_map(|input| [(input, ""), (input, "strict")]) {
+        let chunks = Box::new(Some(Ok(input.to_vec())).into_iter());
+        let encoder = Encoder::new(chunks, Utf8Decode::new(options).unwrap(), "un_utf8",
+                                   ErrorPolicy::Replace);
+        let decoded: String = encoder.map(Result::unwrap).collect::<Vec<_>>().concat()
+            .chunks(4)
+            .map(|c| std::char::from_u32(utils::u32_from_bytes(c, true)).unwrap())
+            .collect();
+        // The standard library does the same as the WHATWG decoder.
+        assert_eq!(String::from_utf8_lossy(input), decoded, "decoding {:x?} with {:?}", input,
+                   options);
+    }
+}
+
+#[test]
+fn test_utf8_strict() {
+    let decode = |options: &str, input: &[u8]| {
+        let chunks = Box::new(Some(Ok(input.to_vec())).into_iter());
+        let encoder = Encoder::new(chunks, Utf8Decode::new(options).unwrap(), "un_utf8",
+                                   ErrorPolicy::Halt);
+        encoder.collect::<Result<Vec<_>, _>>()
+            .map(|chunks| chunks.concat().chunks(4).map(|c| utils::u32_from_bytes(c, true)).collect())
+            .map_err(|e| e.to_string())
+    };
+    let valid = "a\u{E9}\u{20AC}\u{1F600}".as_bytes();
+    let expected: Vec<u32> = vec![0x61, 0xE9, 0x20AC, 0x1F600];
+    assert_eq!(Ok(expected.clone()), decode("strict", valid));
+    assert_eq!(Ok(expected), decode("", valid));
+
+    for bad in [&b"\xC0\xAF"[..], b"\xE0\x80\xAF", b"\xED\xA0\x80", b"\xF4\x90\x80\x80",
+                b"\xF8\x88\x80\x80\x80", b"\x80", b"\xC3\xC0"] {
+        assert!(decode("strict", bad).is_err(), "{:x?} should be invalid", bad);
+    }
+    // Relaxed mode lets the bad sequences through, except for an unfinished one.
+    assert_eq!(Ok(vec![0x2F]), decode("relaxed", b"\xC0\xAF"));
+    assert_eq!(Ok(vec![0xD800]), decode("legacy", b"\xED\xA0\x80"));
+    assert!(decode("relaxed", b"\xC3\xC0").is_err());
+    // A continuation byte on its own is never a sequence, and doesn't take the next byte with it.
+    let chunks = Box::new(Some(Ok(b"\x80\xBFa".to_vec())).into_iter());
+    let encoder = Encoder::new(chunks, Utf8Decode::new("relaxed").unwrap(), "un_utf8",
+                               ErrorPolicy::Replace);
+    assert_eq!(vec![0xFFFD, 0xFFFD, 0x61],
+               encoder.flat_map(Result::unwrap).collect::<Vec<u8>>()
+                   .chunks(4).map(|c| utils::u32_from_bytes(c, true)).collect::<Vec<_>>());
+    // Nor does an unfinished sequence, which doesn't include that byte in the error.
+    for &options in &["strict", "relaxed"] {
+        let chunks = Box::new(Some(Ok(b"\xE2\x82A".to_vec())).into_iter());
+        let mut encoder = Encoder::new(chunks, Utf8Decode::new(options).unwrap(), "un_utf8",
+                                       ErrorPolicy::Halt);
+        let e = encoder.next().unwrap().unwrap_err();
+        assert_eq!(Some(&b"\xE2\x82"[..]), e.bad_bytes(), "with {:?}", options);
+        assert!(e.to_string().contains("expected 3 bytes, only got 2"), "{}", e);
+        assert_eq!(vec![0, 0, 0, b'A'], encoder.next().unwrap().unwrap());
+    }
 }
@@ -1,3 +1,5 @@
+use crate::encoding::*;
+
 use std::convert::TryInto;
 
 pub fn u32_from_bytes(bytes: &[u8], big_endian: bool) -> u32 {
@@ -41,3 +43,88 @@ pub fn unicode_replacement() -> Vec<u8> {
     //u32_to_bytes(::std::char::REPLACEMENT_CHARACTER as u32, true)
     vec![0, 0, 0xFF, 0xFD]
 }
+
+/// Decode everything currently buffered in the input, using a function that maps each byte to a
+/// code point, and return the result as character data (UTF-32BE).
+/// Decoding stops short of the first byte that can't be mapped; if that is the very first byte,
+/// it is consumed and its error is returned instead.
+pub fn decode_single_bytes<F>(input: &mut dyn EncodingInput, mut decode: F)
+        -> Option<Result<Vec<u8>, CodeError>>
+        where F: FnMut(u8) -> Result<u32, CodeError> {
+    let buf = match input.fill_buf() {
+        Ok(buf) => buf,
+        Err(e) => { return Some(Err(e)); },
+    };
+    if buf.is_empty() {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(buf.len() * 4);
+    let mut error = None;
+    for &byte in buf {
+        match decode(byte) {
+            Ok(codepoint) => { out.extend_from_slice(&codepoint.to_be_bytes()); },
+            Err(e) => {
+                error = Some(e);
+                break;
+            },
+        }
+    }
+
+    let decoded = out.len() / 4;
+    match error {
+        Some(e) if decoded == 0 => {
+            input.consume(1);
+            Some(Err(e))
+        },
+        _ => {
+            input.consume(decoded);
+            Some(Ok(out))
+        },
+    }
+}
+
+/// Encode all the complete code points currently buffered in the input, which is character data
+/// (UTF-32BE), using a function that appends the encoding of one code point to the output (and
+/// appends nothing if it fails).
+/// Like `decode_single_bytes`, this stops short of the first code point that can't be encoded,
+/// unless it is the very first one, in which case its error is returned.
+pub fn encode_codepoints<F>(input: &mut dyn EncodingInput, mut encode: F)
+        -> Option<Result<Vec<u8>, CodeError>>
+        where F: FnMut(u32, &mut Vec<u8>) -> Result<(), CodeError> {
+    let buf = match input.fill_buf() {
+        Ok(buf) => buf,
+        Err(e) => { return Some(Err(e)); },
+    };
+
+    if buf.len() < 4 {
+        // Not even one whole code point is buffered; assemble one the slow way.
+        let mut out = vec![];
+        return match input.get_bytes(4) {
+            Some(Ok(bytes)) => Some(encode(u32_from_bytes(&bytes, true), &mut out).map(|()| out)),
+            other => other,
+        };
+    }
+
+    let mut out = Vec::with_capacity(buf.len());
+    let mut encoded = 0;
+    let mut error = None;
+    for bytes in buf.chunks_exact(4) {
+        if let Err(e) = encode(u32_from_bytes(bytes, true), &mut out) {
+            error = Some(e);
+            break;
+        }
+        encoded += 1;
+    }
+
+    match error {
+        Some(e) if encoded == 0 => {
+            input.consume(4);
+            Some(Err(e))
+        },
+        _ => {
+            input.consume(encoded * 4);
+            Some(Ok(out))
+        },
+    }
+}
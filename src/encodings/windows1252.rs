@@ -22,11 +22,21 @@ impl EncodingStatics for Windows1252Encode {
 
     fn print_help() {
         println!("Encodes character data as Windows-1252 (aka CP1252).");
-        println!("Un-mapped characters raise a warning and are replaced with '?'.");
+        println!("Un-mapped characters are an error; the replacement character is '?'.");
         println!("(no options)");
     }
 }
 
+impl Windows1252Encode {
+    /// The byte for a code point in Windows 1252, if it has one.
+    pub fn encode_codepoint(codepoint: u32) -> Option<u8> {
+        if codepoint < 0x80 || (0xA0..=0xFF).contains(&codepoint) {
+            return Some(codepoint as u8);
+        }
+        MAPPING.iter().position(|&from| from == codepoint).map(|idx| 0x80 + idx as u8)
+    }
+}
+
 impl Encoding for Windows1252Encode {
     fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
         let codepoint = match input.get_bytes(4) {
@@ -37,16 +47,13 @@ impl Encoding for Windows1252Encode {
             None => { return None; },
         };
 
-        if codepoint < 0x80 || (0xA0..=0xFF).contains(&codepoint) {
-            debug!("U+{:04X} identity mapping", codepoint);
-            return Some(Ok(vec![codepoint as u8]));
-        }
-
-        let mapped = match MAPPING.iter().enumerate().find(|&(_idx, from)| *from == codepoint) {
-            Some((idx, _from)) => 0x80 + (idx as u8),
+        let mapped = match Self::encode_codepoint(codepoint) {
+            Some(mapped) => mapped,
             None => {
-                warn!("cannot map Unicode code point U+{:04X} into Windows 1252", codepoint);
-                return Some(Ok(vec![REPLACEMENT]));
+                return Some(Err(CodeError::new(
+                    format!("cannot map Unicode code point U+{:04X} into Windows 1252", codepoint))
+                    .with_bytes(utils::u32_to_bytes(codepoint, true))
+                    .with_unmappable(codepoint)));
             }
         };
 
@@ -72,30 +79,29 @@ impl EncodingStatics for Windows1252Decode {
     }
 }
 
-impl Encoding for Windows1252Decode {
-    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
-        let byte = match input.get_byte() {
-            Some(Ok(byte)) => byte,
-            Some(Err(e)) => { return Some(Err(e)); }
-            None => { return None; }
-        };
-
+impl Windows1252Decode {
+    fn decode_byte(byte: u8) -> Result<u32, CodeError> {
         if !(0x80..0xA0).contains(&byte) {
-            debug!("U+{:04X} identity encoding", byte);
-            return Some(Ok(utils::u32_to_bytes(byte as u32, true)));
+            return Ok(byte as u32);
         }
 
-        let codepoint = match MAPPING[byte as usize - 0x80] {
+        match MAPPING[byte as usize - 0x80] {
             UNDEF => {
                 let msg = format!("Undefined Windows 1252 code unit {:#04X}", byte);
                 error!("{}", msg);
-                return Some(Err(CodeError::new(msg).with_bytes(vec![byte])));
+                Err(CodeError::new(msg).with_bytes(vec![byte]))
             }
-            codepoint => codepoint,
-        };
+            codepoint => {
+                debug!("{:#04X} maps to U+{:04X}", byte, codepoint);
+                Ok(codepoint)
+            }
+        }
+    }
+}
 
-        debug!("{:#04X} maps to U+{:04X}", byte, codepoint);
-        Some(Ok(utils::u32_to_bytes(codepoint, true)))
+impl Encoding for Windows1252Decode {
+    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
+        utils::decode_single_bytes(input, Self::decode_byte)
     }
 
     fn replacement(&self) -> Vec<u8> {
@@ -0,0 +1,219 @@
+//! Adapters between unicoder and the standard I/O traits.
+
+use std::io::{self, Read, Write};
+use std::sync::mpsc;
+use std::thread;
+
+use crate::encoding::*;
+use crate::pipeline::Pipeline;
+
+/// The default size of the chunks read by `ReadChunks`.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Turns any reader into a source of chunks of bytes, suitable as input to an `Encoder`.
+pub struct ReadChunks<R> {
+    reader: R,
+    chunk_size: usize,
+}
+
+impl<R: Read> ReadChunks<R> {
+    /// Read from the given reader in chunks of up to `DEFAULT_CHUNK_SIZE` bytes.
+    pub fn new(reader: R) -> ReadChunks<R> {
+        Self::with_chunk_size(reader, DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Read from the given reader in chunks of up to the given size.
+    pub fn with_chunk_size(reader: R, chunk_size: usize) -> ReadChunks<R> {
+        assert!(chunk_size > 0, "chunk size must be nonzero");
+        ReadChunks {
+            reader,
+            chunk_size,
+        }
+    }
+}
+
+impl<R: Read> Iterator for ReadChunks<R> {
+    type Item = Result<Vec<u8>, CodeError>;
+    fn next(&mut self) -> Option<Result<Vec<u8>, CodeError>> {
+        let mut chunk = vec![0u8; self.chunk_size];
+        loop {
+            match self.reader.read(&mut chunk) {
+                Ok(0) => { return None; },
+                Ok(n) => {
+                    chunk.truncate(n);
+                    return Some(Ok(chunk));
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    return Some(Err(CodeError::new("I/O error reading input").with_inner(e)));
+                },
+            }
+        }
+    }
+}
+
+fn io_error(e: CodeError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// A reader which yields the output of an `Encoder`.
+pub struct EncoderReader {
+    encoder: Encoder,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl EncoderReader {
+    /// Read the output of the given encoder.
+    pub fn new(encoder: Encoder) -> EncoderReader {
+        EncoderReader {
+            encoder,
+            chunk: vec![],
+            pos: 0,
+        }
+    }
+
+    /// Read from the given reader, through the given pipeline.
+    pub fn from_read<R: Read + 'static>(reader: R, pipeline: &Pipeline)
+            -> Result<EncoderReader, String> {
+        let encoder = pipeline.build(Box::new(ReadChunks::new(reader)))?;
+        Ok(EncoderReader::new(encoder))
+    }
+}
+
+impl Read for EncoderReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.encoder.next() {
+                Some(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                },
+                Some(Err(e)) => { return Err(io_error(e)); },
+                None => { return Ok(0); },
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[.. n].copy_from_slice(&self.chunk[self.pos .. self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// How many chunks written to an `EncoderWriter` can be waiting to go through its pipeline.
+const WRITER_QUEUE_LENGTH: usize = 16;
+
+/// A writer which passes everything written to it through a pipeline, and writes the output to
+/// another writer (the sink).
+///
+/// Because an encoding may need to see more input before it can produce any output, the pipeline
+/// runs on its own thread, and reads from a queue of the data written so far. Call `finish` to
+/// mark the end of the input and get any errors; otherwise they are discarded when the writer is
+/// dropped.
+pub struct EncoderWriter<W> {
+    sender: Option<mpsc::SyncSender<Vec<u8>>>,
+    worker: Option<thread::JoinHandle<io::Result<W>>>,
+}
+
+impl<W: Write + Send + 'static> EncoderWriter<W> {
+    /// Write through the given pipeline into the given sink.
+    pub fn new(sink: W, pipeline: &Pipeline) -> EncoderWriter<W> {
+        let pipeline = pipeline.clone();
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(WRITER_QUEUE_LENGTH);
+        let worker = thread::spawn(move || {
+            let mut sink = sink;
+            let input = Box::new(receiver.into_iter().map(Ok));
+            let encoder = pipeline.build(input)
+                .map_err(|msg| io::Error::new(io::ErrorKind::InvalidInput, msg))?;
+            for result in encoder {
+                sink.write_all(&result.map_err(io_error)?)?;
+            }
+            sink.flush()?;
+            Ok(sink)
+        });
+
+        EncoderWriter {
+            sender: Some(sender),
+            worker: Some(worker),
+        }
+    }
+
+    /// Signal the end of the input, wait for the pipeline to process everything, and return the
+    /// sink, or the first error encountered.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.sender = None;
+        self.join()
+    }
+
+    fn join(&mut self) -> io::Result<W> {
+        match self.worker.take() {
+            Some(worker) => worker.join().unwrap_or_else(|_| {
+                Err(io::Error::new(io::ErrorKind::Other, "encoder thread panicked"))
+            }),
+            None => Err(io::Error::new(io::ErrorKind::Other, "encoder has already failed")),
+        }
+    }
+}
+
+impl<W: Write + Send + 'static> Write for EncoderWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let sent = match self.sender {
+            Some(ref sender) => sender.send(buf.to_vec()).is_ok(),
+            None => false,
+        };
+        if sent {
+            Ok(buf.len())
+        } else {
+            // The pipeline has stopped early, which means it hit an error.
+            self.sender = None;
+            self.join().and(Err(io::Error::new(io::ErrorKind::Other,
+                                               "encoder stopped unexpectedly")))
+        }
+    }
+
+    /// Data written is handed to the pipeline right away, but this can't force it out the other
+    /// end: an encoding may be waiting on more input to finish what it has. Use `finish` for that.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<W> Drop for EncoderWriter<W> {
+    fn drop(&mut self) {
+        self.sender = None;
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[test]
+fn test_encoder_reader() {
+    let pipeline = Pipeline::parse(["un_utf8", "utf16,be"], ErrorPolicy::Halt).unwrap();
+    let input = io::Cursor::new(b"h\xC3\xA9!".to_vec());
+    let mut reader = EncoderReader::from_read(input, &pipeline).unwrap();
+    let mut output = vec![];
+    reader.read_to_end(&mut output).unwrap();
+    assert_eq!(&[0, b'h', 0, 0xE9, 0, b'!'], output.as_slice());
+
+    let input = io::Cursor::new(b"\xFF".to_vec());
+    let mut reader = EncoderReader::from_read(input, &pipeline).unwrap();
+    let e = reader.read_to_end(&mut output).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidData, e.kind());
+}
+
+#[test]
+fn test_encoder_writer() {
+    let pipeline = Pipeline::parse(["un_utf8", "utf16,be"], ErrorPolicy::Halt).unwrap();
+    let mut writer = EncoderWriter::new(vec![], &pipeline);
+    // Split up a multi-byte sequence between writes.
+    for byte in b"h\xC3\xA9!" {
+        writer.write_all(&[*byte]).unwrap();
+    }
+    let output = writer.finish().unwrap();
+    assert_eq!(&[0, b'h', 0, 0xE9, 0, b'!'], output.as_slice());
+
+    let mut writer = EncoderWriter::new(vec![], &pipeline);
+    writer.write_all(b"\xFF").unwrap();
+    assert_eq!(io::ErrorKind::InvalidData, writer.finish().unwrap_err().kind());
+}
@@ -0,0 +1,19 @@
+//! unicoder: a library for converting data between character encodings, and for inspecting it
+//! along the way.
+//!
+//! Conversions are built as a chain of [`Encoder`](encoding/struct.Encoder.html)s, each of which
+//! applies one [`Encoding`](encoding/trait.Encoding.html) to the output of the previous one.
+//! Encodings are looked up by name in the registry in the [`encodings`](encodings/index.html)
+//! module.
+
+#![allow(clippy::new_ret_no_self)]
+#![deny(rust_2018_idioms)]
+
+#[macro_use]
+extern crate log;
+
+pub mod batch;
+pub mod encoding;
+pub mod encodings;
+pub mod io;
+pub mod pipeline;
@@ -1,4 +1,3 @@
-#![allow(clippy::new_ret_no_self)]
 #![deny(rust_2018_idioms)]
 
 #[macro_use]
@@ -6,52 +5,29 @@ extern crate log;
 
 use std::collections::VecDeque;
 use std::env;
+use std::fs::File;
 use std::io::{self, Read, Write};
+use std::path::PathBuf;
 use std::process;
 
-mod encoding;
-use encoding::*;
-
-mod encodings;
-use encodings::*;
-
-struct StdinCode {
-    input: io::Bytes<io::Stdin>,
-}
-
-impl Iterator for StdinCode {
-    type Item = Result<u8, CodeError>;
-    fn next(&mut self) -> Option<Result<u8, CodeError>> {
-        self.input.next().map(|result|
-            result.map_err(|ioerr|
-                CodeError::new("I/O error in stdin").with_inner(ioerr)))
-    }
-}
-
-struct IdentityEncoding;
-
-impl Encoding for IdentityEncoding {
-    fn next(&mut self, input: &mut dyn EncodingInput) -> Option<Result<Vec<u8>, CodeError>> {
-        input.get_bytes(1)
-    }
-}
+use unicoder::batch::{self, BatchOptions, FileOutcome};
+use unicoder::encoding::*;
+use unicoder::encodings::*;
+use unicoder::io::ReadChunks;
+use unicoder::pipeline::{self, Pipeline};
 
 struct DebugOutput {
-    debug_output: bool
+    level: log::Level,
 }
 
 impl log::Log for DebugOutput {
     fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
-        if !self.debug_output {
-            metadata.level() <= log::Level::Warn
-        } else {
-            true
-        }
+        metadata.level() <= self.level
     }
 
     fn log(&self, record: &log::Record<'_>) {
         if self.enabled(record.metadata()) {
-            println!("{}: {}: {}", record.target(), record.level(), record.args());
+            eprintln!("{}: {}: {}", record.target(), record.level(), record.args());
         }
     }
 
@@ -59,27 +35,84 @@ impl log::Log for DebugOutput {
 }
 
 impl DebugOutput {
+    /// Set up logging: debug output logs everything, otherwise (verbose output) informational
+    /// messages, warnings and errors are logged.
     pub fn init(debug: bool) {
-        log::set_max_level(if debug {
-            log::LevelFilter::Debug
-        } else {
-            log::LevelFilter::Warn
-        });
-        log::set_boxed_logger(Box::new(DebugOutput { debug_output: debug }))
+        let level = if debug { log::Level::Debug } else { log::Level::Info };
+        log::set_max_level(level.to_level_filter());
+        log::set_boxed_logger(Box::new(DebugOutput { level }))
             .expect("failed to initialize logging");
     }
 }
 
-pub fn parse_error_policy(s: &str) -> Option<ErrorPolicy> {
-    if s == "halt" {
-        Some(ErrorPolicy::Halt)
-    } else if s == "skip" {
-        Some(ErrorPolicy::Skip)
-    } else if s == "replace" {
-        Some(ErrorPolicy::Replace)
-    } else {
-        None
+/// Convert the given files, and the files in the given directories, in place, printing what
+/// happened to each one. Doesn't return.
+fn convert_in_place(paths: &[String], pipeline: &Pipeline, options: &BatchOptions) {
+    if paths.is_empty() || paths.iter().any(|path| path == "-") {
+        println!("--in-place needs input files or directories, given with -i");
+        process::exit(-1);
+    }
+    let paths: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+    let files = batch::find_files(&paths, options).unwrap_or_else(|e| {
+        println!("Error finding files to convert: {}", e);
+        process::exit(1);
+    });
+
+    let mut converted = 0;
+    for file in &files {
+        pipeline.report().set_input_name(file.to_string_lossy());
+        let outcome = batch::convert_file(file, pipeline, options);
+        println!("{}: {}", file.display(), outcome);
+        if let FileOutcome::Converted { .. } = outcome {
+            converted += 1;
+        }
+    }
+    println!("{} of {} files converted", converted, files.len());
+
+    if !pipeline.report().is_empty() {
+        eprint!("\n{}", pipeline.report());
+    }
+    process::exit(if converted == files.len() && pipeline.report().is_empty() { 0 } else { 1 });
+}
+
+/// Parse bytes written in hex, ignoring whitespace.
+fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<char> = s.chars().filter(|c| !c.is_whitespace()).collect();
+    if digits.len() % 2 != 0 {
+        return Err("odd number of hex digits".to_owned());
+    }
+    digits.chunks(2)
+        .map(|pair| {
+            let pair: String = pair.iter().collect();
+            u8::from_str_radix(&pair, 16).map_err(|_| format!("{:?} is not a hex byte", pair))
+        })
+        .collect()
+}
+
+/// Parse code points separated by whitespace or commas, written in hex with an optional "U+"
+/// or "0x" in front, into character data.
+fn parse_codepoints(s: &str) -> Result<Vec<u8>, String> {
+    let mut chars = vec![];
+    for code in s.split(|c: char| c.is_whitespace() || c == ',').filter(|code| !code.is_empty()) {
+        let hex = ["U+", "u+", "0x", "0X"].iter()
+            .find_map(|prefix| code.strip_prefix(prefix))
+            .unwrap_or(code);
+        match u32::from_str_radix(hex, 16) {
+            Ok(codepoint) if codepoint <= 0x10FFFF => {
+                chars.extend_from_slice(&codepoint.to_be_bytes());
+            },
+            _ => { return Err(format!("{:?} is not a code point", code)); },
+        }
     }
+    Ok(chars)
+}
+
+/// Take the argument for an option which has one.
+fn option_value(option: &str, args: &mut VecDeque<String>) -> String {
+    args.pop_front().unwrap_or_else(|| {
+        println!("{} requires an argument", option);
+        process::exit(-1);
+    })
 }
 
 fn main() {
@@ -97,6 +130,13 @@ fn main() {
     let program_name = args.pop_front().unwrap();
 
     let mut error_policy = ErrorPolicy::Halt;
+    let mut replacement = None;
+    let mut inputs = vec![];
+    let mut output_path = None;
+    let mut in_place = false;
+    let mut literal: Option<(&str, Vec<u8>, StreamKind)> = None;
+    let mut batch_options = BatchOptions::default();
+    let mut check = None;
 
     while !args.is_empty() {
         let arg = args.pop_front().unwrap();
@@ -107,6 +147,8 @@ fn main() {
                         'd' => { debug = true; },
                         'v' => { verbose = true; },
                         'h' => { help = true; },
+                        'i' => { inputs.push(option_value("-i", &mut args)); },
+                        'o' => { output_path = Some(option_value("-o", &mut args)); },
                         _ => {
                             println!("unknown flag {:?}", c);
                             process::exit(-1);
@@ -121,11 +163,58 @@ fn main() {
                 list = true;
             } else if arg == "--help" {
                 help = true;
+            } else if arg == "--input" {
+                inputs.push(option_value("--input", &mut args));
+            } else if let Some(path) = arg.strip_prefix("--input=") {
+                inputs.push(path.to_owned());
+            } else if arg == "--output" {
+                output_path = Some(option_value("--output", &mut args));
+            } else if let Some(path) = arg.strip_prefix("--output=") {
+                output_path = Some(path.to_owned());
+            } else if let Some(option) = ["--text", "--hex", "--codepoints"].iter()
+                    .find(|option| arg == **option || arg.starts_with(&format!("{}=", option))) {
+                let value = match arg.split_once('=') {
+                    Some((_, value)) => value.to_owned(),
+                    None => option_value(option, &mut args),
+                };
+                let parsed = match *option {
+                    "--text" => Ok((value.into_bytes(), StreamKind::Bytes)),
+                    "--hex" => parse_hex(&value).map(|bytes| (bytes, StreamKind::Bytes)),
+                    _ => parse_codepoints(&value).map(|bytes| (bytes, StreamKind::Chars)),
+                };
+                let (bytes, kind) = parsed.unwrap_or_else(|msg| {
+                    println!("invalid {} argument: {}", option, msg);
+                    process::exit(-1);
+                });
+                if literal.is_some() {
+                    println!("only one of --text, --hex and --codepoints can be given, once");
+                    process::exit(-1);
+                }
+                literal = Some((option, bytes, kind));
+            } else if arg == "--in-place" {
+                in_place = true;
+            } else if let Some(glob) = arg.strip_prefix("--include=") {
+                batch_options.include.push(glob.to_owned());
+            } else if let Some(glob) = arg.strip_prefix("--exclude=") {
+                batch_options.exclude.push(glob.to_owned());
+            } else if arg == "--backup" {
+                batch_options.backup_suffix = Some(".bak".to_owned());
+            } else if let Some(suffix) = arg.strip_prefix("--backup=") {
+                batch_options.backup_suffix = Some(suffix.to_owned());
+            } else if arg == "--check" {
+                check = Some(ErrorPolicy::Halt);
+            } else if arg == "--check=all" {
+                check = Some(ErrorPolicy::Report);
             } else if let Some(s) = arg.strip_prefix("--errors=") {
-                error_policy = parse_error_policy(s).unwrap_or_else(|| {
+                error_policy = ErrorPolicy::parse(s).unwrap_or_else(|| {
                     println!("invalid error policy");
                     process::exit(-1);
                 });
+            } else if let Some(s) = arg.strip_prefix("--replacement=") {
+                replacement = Some(pipeline::unescape(s).unwrap_or_else(|msg| {
+                    println!("invalid replacement: {}", msg);
+                    process::exit(-1);
+                }));
             } else {
                 println!("unknown option {:?}", arg);
                 process::exit(-1);
@@ -156,12 +245,61 @@ fn main() {
             println!("       {} --list", program_name);
             println!("options:");
             println!("      -d | --debug        enable stderr debug output logging");
-            println!("      -v | --verbose      enable stderr error output logging");
+            println!("      -v | --verbose      enable stderr error and info output logging");
+            println!("      -i | --input <file> read input from a file instead of stdin ('-' means");
+            println!("                            stdin); can be given more than once, to process");
+            println!("                            several inputs one after another");
+            println!("      -o | --output <file>");
+            println!("                          write output to a file instead of stdout ('-' means");
+            println!("                            stdout)");
+            println!("      --text <text>       use the given text (as UTF-8) as the input");
+            println!("      --hex <hex>         use the given bytes as the input, written in hex");
+            println!("                            (e.g. 'de ad be ef')");
+            println!("      --codepoints <codes>");
+            println!("                          use the given code points as the input, as");
+            println!("                            character data (e.g. 'U+1F600 U+E9')");
+            println!("                          (only one of --text, --hex and --codepoints can be");
+            println!("                            given)");
+            println!("      --in-place          convert the input files in place; input directories");
+            println!("                            are searched recursively for files to convert.");
+            println!("                            Files which fail to convert are left unchanged.");
+            println!("      --include=<glob>    with --in-place, only convert files matching the");
+            println!("                            glob (e.g. '*.txt' or 'src/**/*.c')");
+            println!("      --exclude=<glob>    with --in-place, skip files and directories matching");
+            println!("                            the glob");
+            println!("      --backup[=<suffix>] with --in-place, keep the original files, with the");
+            println!("                            suffix added to their names (default .bak)");
+            println!("      --check[=all]       only check that the input is valid for the pipeline:");
+            println!("                            discard the output, and print the first error");
+            println!("                            (or with =all, every error) in each input, or");
+            println!("                            'ok'. Exits with status 0 if all inputs are valid");
+            println!("                            and 1 otherwise. Overrides --errors.");
             println!("      --errors=<error policy>");
+            println!("          (can also be set for one encoding with the option errors=<policy>)");
             println!("          halt            exit on errors (default)");
             println!("          skip            skip over erroneous input");
             println!("          replace         substitute with the encoding's replacement");
             println!("                            character (e.g. U+FFFD or '?')");
+            println!("          report          like replace, but print a summary of all errors");
+            println!("                            at the end, and exit with an error status");
+            println!("          xmlcharrefreplace");
+            println!("                          replace characters that can't be encoded with XML");
+            println!("                            character references (e.g. &#x1F600;)");
+            println!("          backslashreplace");
+            println!("                          replace characters that can't be encoded with");
+            println!("                            backslash escapes (e.g. \\U0001f600), and invalid");
+            println!("                            input with escapes of its bytes (e.g. \\xff)");
+            println!("          namereplace     replace characters that can't be encoded with their");
+            println!("                            names (e.g. \\N{{GRINNING FACE}})");
+            println!("          surrogateescape");
+            println!("                          decode invalid non-ASCII bytes to U+DC00 + the byte,");
+            println!("                            and encode those code points back to the original");
+            println!("                            bytes");
+            println!("      --replacement=<text>");
+            println!("          text to substitute for errors instead of the encoding's replacement");
+            println!("          character; each encoding outputs it in its own terms (e.g. encoded");
+            println!("          as ISO 8859-1). May use the escapes \\xHH and \\u{{HHHH}}.");
+            println!("          (can also be set for one encoding with the option replace=<text>)");
         }
         process::exit(-1);
     }
@@ -172,32 +310,131 @@ fn main() {
         process::exit(-1);
     }
 
+    if let Some(policy) = check {
+        if in_place || output_path.is_some() {
+            println!("--check can't be used with --in-place or --output");
+            process::exit(-1);
+        }
+        error_policy = policy;
+    }
+
     debug!("using error policy {:?}", error_policy);
 
-    let stdin = Box::new(StdinCode { input: io::stdin().bytes() });
-    let mut encoder: Box<Encoder> = Box::new(Encoder::new(stdin, Box::new(IdentityEncoding), "stdin", error_policy));
-    for encoding_name in args {
-        debug!("encoding: {}", encoding_name);
-        let parts: Vec<&str> = encoding_name.splitn(2, ',').collect();
-        let encoding = match get_encoding(parts[0], parts.get(1).unwrap_or(&"")) {
-            Ok(enc) => enc,
-            Err(msg) => {
-                println!("Error setting up {}: {}", parts[0], msg);
-                process::exit(-1);
+    let mut pipeline = Pipeline::parse(&args, error_policy).unwrap_or_else(|msg| {
+        println!("{}", msg);
+        process::exit(-1);
+    });
+    if let Some(replacement) = replacement {
+        pipeline = pipeline.with_replacement(replacement);
+    }
+    if let Some(policy) = check {
+        // Errors mustn't go unnoticed because a stage says to replace or skip them.
+        pipeline = pipeline.with_forced_error_policy(policy);
+    }
+
+    if literal.is_some() && (!inputs.is_empty() || in_place) {
+        println!("--text, --hex and --codepoints can't be used with input files");
+        process::exit(-1);
+    }
+
+    let source_kind = literal.as_ref().map(|(_, _, kind)| *kind).unwrap_or(StreamKind::Bytes);
+    match pipeline.validate(source_kind) {
+        Ok(warnings) => {
+            for warning in warnings {
+                eprintln!("warning: {}", warning);
             }
-        };
-        encoder = Box::new(Encoder::new(encoder, encoding, encoding_name.as_str(), error_policy));
+        },
+        Err(msg) => {
+            println!("Invalid pipeline: {}", msg);
+            process::exit(-1);
+        },
+    }
+
+    if in_place {
+        if output_path.is_some() {
+            println!("--in-place and --output can't be used together");
+            process::exit(-1);
+        }
+        convert_in_place(&inputs, &pipeline, &batch_options);
+    }
+
+    let mut output: Box<dyn Write> = match output_path.as_deref() {
+        _ if check.is_some() => Box::new(io::sink()),
+        None | Some("-") => Box::new(io::stdout()),
+        Some(path) => Box::new(File::create(path).unwrap_or_else(|e| {
+            println!("Error opening {} for output: {}", path, e);
+            process::exit(-1);
+        })),
+    };
+
+    if inputs.is_empty() {
+        inputs.push("-".to_owned());
     }
 
-    loop {
-        match encoder.next() {
-            None => { break; },
-            Some(Ok(byte)) => { io::stdout().write_all(&[byte]).unwrap(); },
-            Some(Err(e)) => {
-                println!("\nError processing input:\n{}", e);
-                println!("terminating.");
+    let mut invalid = false;
+    'inputs: for path in &inputs {
+        let (reader, name): (Box<dyn Read>, &str) = if let Some((option, bytes, _)) = literal.take() {
+            (Box::new(io::Cursor::new(bytes)), option)
+        } else if path == "-" {
+            (Box::new(io::stdin()), "stdin")
+        } else {
+            let file = File::open(path).unwrap_or_else(|e| {
+                println!("Error opening {}: {}", path, e);
                 process::exit(1);
-            },
+            });
+            (Box::new(file), path)
+        };
+        debug!("processing {}", name);
+        if inputs.len() > 1 {
+            pipeline.report().set_input_name(name);
         }
+
+        let source = Box::new(ReadChunks::new(reader));
+        let source = Box::new(Encoder::new(source, Box::new(IdentityEncoding), name, error_policy)
+                              .with_report(pipeline.report().clone()));
+        let encoder = pipeline.build(source).unwrap_or_else(|msg| {
+            println!("{}", msg);
+            process::exit(-1);
+        });
+
+        let errors_before = pipeline.report().len();
+        for result in encoder {
+            match result {
+                Ok(chunk) => { output.write_all(&chunk).unwrap(); },
+                Err(e) if check.is_some() => {
+                    println!("{}: invalid: {}", name, e);
+                    invalid = true;
+                    continue 'inputs;
+                },
+                Err(e) => {
+                    output.flush().unwrap();
+                    println!("\nError processing {}:\n{}", name, e);
+                    println!("terminating.");
+                    process::exit(1);
+                },
+            }
+        }
+
+        if check.is_some() {
+            match pipeline.report().len() - errors_before {
+                0 => println!("{}: ok", name),
+                1 => println!("{}: 1 error", name),
+                errors => println!("{}: {} errors", name, errors),
+            }
+        }
+    }
+
+    output.flush().unwrap();
+
+    if check.is_some() {
+        if !pipeline.report().is_empty() {
+            print!("\n{}", pipeline.report());
+        }
+        process::exit(if invalid || !pipeline.report().is_empty() { 1 } else { 0 });
+    }
+
+    if !pipeline.report().is_empty() {
+        eprint!("\n{}", pipeline.report());
+        process::exit(1);
     }
 }
@@ -0,0 +1,479 @@
+//! Chains of encodings, described by name so that they can be set up as many times as needed.
+
+use std::fmt;
+
+use crate::encoding::*;
+use crate::encodings::{get_encoding, get_stream_kinds};
+
+/// One stage of a pipeline: the name of an encoding, and the options to give it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stage {
+    pub name: String,
+    pub options: String,
+
+    /// The error policy for this stage, if it overrides the pipeline's.
+    pub error_policy: Option<ErrorPolicy>,
+
+    /// The text to put in place of errors for this stage, if it overrides the pipeline's.
+    pub replacement: Option<String>,
+}
+
+impl Stage {
+    /// Parse a stage from the command-line syntax: `<encoding>[,option,...]`.
+    /// An `errors=<policy>` option sets the stage's error policy, and a `replace=<text>` option
+    /// its replacement text (see `unescape`); these aren't passed on to the encoding.
+    pub fn parse(spec: &str) -> Result<Stage, String> {
+        let mut parts = spec.split(',');
+        let name = parts.next().unwrap().to_owned();
+        let mut options = vec![];
+        let mut error_policy = None;
+        let mut replacement = None;
+        for option in parts {
+            if let Some(policy) = option.strip_prefix("errors=") {
+                error_policy = Some(ErrorPolicy::parse(policy)
+                    .ok_or_else(|| format!("invalid error policy {:?}", policy))?);
+            } else if let Some(text) = option.strip_prefix("replace=") {
+                replacement = Some(unescape(text)?);
+            } else {
+                options.push(option);
+            }
+        }
+        Ok(Stage {
+            name,
+            options: options.join(","),
+            error_policy,
+            replacement,
+        })
+    }
+
+    /// Make a new instance of the encoding for this stage.
+    pub fn instantiate(&self) -> Result<Box<dyn Encoding>, String> {
+        get_encoding(&self.name, &self.options)
+    }
+
+    /// Make a new instance of the encoding for this stage, to be used with the given error policy,
+    /// checking that the two can work together.
+    fn instantiate_for(&self, error_policy: ErrorPolicy) -> Result<Box<dyn Encoding>, String> {
+        let encoding = self.instantiate()?;
+        if error_policy == ErrorPolicy::SurrogateEscape && encoding.decodes_invalid_input() {
+            return Err(format!("{} decodes some invalid input to characters, so it can't be used \
+                                with errors=surrogateescape", self));
+        }
+        Ok(encoding)
+    }
+
+    /// Turn replacement text into what this stage should output in place of an error: character
+    /// data as-is, encoded by the stage's own encoding if it takes character data, text as UTF-8,
+    /// and bytes as the characters' values, which must then all be below U+0100.
+    pub fn replacement_output(&self, text: &str) -> Result<Vec<u8>, String> {
+        let (input, output) = get_stream_kinds(&self.name)?;
+        let chars = text.chars().flat_map(|c| (c as u32).to_be_bytes()).collect::<Vec<u8>>();
+        match (input, output) {
+            (_, StreamKind::Chars) => Ok(chars),
+            (StreamKind::Chars, _) => {
+                let source = Box::new(Some(Ok(chars)).into_iter());
+                let mut encoded = vec![];
+                for result in Encoder::new(source, self.instantiate()?, self.to_string(),
+                                           ErrorPolicy::Halt) {
+                    match result {
+                        Ok(bytes) => encoded.extend(bytes),
+                        Err(e) => {
+                            return Err(format!("replacement {:?} can't be encoded: {}", text, e));
+                        },
+                    }
+                }
+                Ok(encoded)
+            },
+            (_, StreamKind::Text) => Ok(text.as_bytes().to_vec()),
+            (_, StreamKind::Bytes) => text.chars()
+                .map(|c| if (c as u32) < 0x100 {
+                    Ok(c as u32 as u8)
+                } else {
+                    Err(format!("replacement {:?} for {} has a character above U+00FF", text,
+                                self))
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Interpret the escapes that replacement text can use: `\\`, `\n`, `\t`, `\xHH` for the
+/// character U+00HH, and `\u{H...}` for any character. Commas separate options, so they have to
+/// be written as `\x2C`.
+pub fn unescape(s: &str) -> Result<String, String> {
+    let mut result = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        let hex = match chars.next() {
+            Some('\\') => { result.push('\\'); continue; },
+            Some('n') => { result.push('\n'); continue; },
+            Some('t') => { result.push('\t'); continue; },
+            Some('x') => chars.by_ref().take(2).collect::<String>(),
+            Some('u') => {
+                if chars.next() != Some('{') {
+                    return Err(format!("expected '{{' after \\u in {:?}", s));
+                }
+                chars.by_ref().take_while(|&c| c != '}').collect::<String>()
+            },
+            _ => { return Err(format!("invalid escape in {:?}", s)); },
+        };
+        match u32::from_str_radix(&hex, 16).ok().and_then(std::char::from_u32) {
+            Some(c) => result.push(c),
+            None => { return Err(format!("invalid character code {:?} in {:?}", hex, s)); },
+        }
+    }
+    Ok(result)
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        if self.options.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{},{}", self.name, self.options)
+        }
+    }
+}
+
+/// A sequence of encodings, each of which is applied to the output of the one before.
+///
+/// A pipeline only describes the encodings; `build` sets up fresh instances of them each time it
+/// is called, so the same pipeline can be used for any number of inputs.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    stages: Vec<Stage>,
+    error_policy: ErrorPolicy,
+    replacement: Option<String>,
+    report: ErrorReport,
+}
+
+impl Pipeline {
+    /// Make an empty pipeline, which will use the given error policy for any stages that don't
+    /// set their own.
+    pub fn new(error_policy: ErrorPolicy) -> Pipeline {
+        Pipeline {
+            stages: vec![],
+            error_policy,
+            replacement: None,
+            report: ErrorReport::new(),
+        }
+    }
+
+    /// Use the given text in place of errors, in any stages that don't set their own, instead of
+    /// each encoding's default replacement.
+    pub fn with_replacement(mut self, replacement: String) -> Pipeline {
+        self.replacement = Some(replacement);
+        self
+    }
+
+    /// Use the given error policy for every stage, overriding any that the stages set themselves.
+    pub fn with_forced_error_policy(mut self, error_policy: ErrorPolicy) -> Pipeline {
+        self.error_policy = error_policy;
+        for stage in &mut self.stages {
+            stage.error_policy = None;
+        }
+        self
+    }
+
+    /// Make a pipeline from a list of stages in the command-line syntax. Each encoding is set up
+    /// once to check that it exists and that its options are valid.
+    pub fn parse<I, S>(specs: I, error_policy: ErrorPolicy) -> Result<Pipeline, String>
+        where I: IntoIterator<Item = S>,
+              S: AsRef<str>,
+    {
+        let mut pipeline = Pipeline::new(error_policy);
+        for spec in specs {
+            let stage = Stage::parse(spec.as_ref())
+                .map_err(|msg| format!("Error setting up {}: {}", spec.as_ref(), msg))?;
+            if let Err(msg) = stage.instantiate_for(stage.error_policy.unwrap_or(error_policy)) {
+                return Err(format!("Error setting up {}: {}", stage.name, msg));
+            }
+            if let Some(ref replacement) = stage.replacement {
+                stage.replacement_output(replacement)
+                    .map_err(|msg| format!("Error setting up {}: {}", stage.name, msg))?;
+            }
+            pipeline.push(stage);
+        }
+        Ok(pipeline)
+    }
+
+    /// Add a stage to the end of the pipeline.
+    pub fn push(&mut self, stage: Stage) {
+        self.stages.push(stage);
+    }
+
+    /// The report that every encoder built from this pipeline (or a clone of it) records errors
+    /// in, under the `Report` error policy.
+    pub fn report(&self) -> &ErrorReport {
+        &self.report
+    }
+
+    /// The stages of the pipeline, in order.
+    pub fn stages(&self) -> &[Stage] {
+        &self.stages
+    }
+
+    /// Check that each stage gets the kind of data it expects, given the kind of data the input to
+    /// the pipeline will be. Combinations which can't work are an error; ones that only work for
+    /// some data (like treating raw bytes as character data) are returned as warnings.
+    /// `build` doesn't do this check itself.
+    pub fn validate(&self, source: StreamKind) -> Result<Vec<String>, String> {
+        let mut warnings = vec![];
+        let mut previous: Option<&Stage> = None;
+        let mut kind = source;
+        for (i, stage) in self.stages.iter().enumerate() {
+            let (input, output) = get_stream_kinds(&stage.name)?;
+            let producer = match previous {
+                Some(previous) => format!("{} (stage {}) produces {}", previous, i, kind),
+                None => format!("the input is {}", kind),
+            };
+            let msg = format!("{} (stage {}) expects {}, but {}", stage, i + 1, input, producer);
+            match (kind, input) {
+                (StreamKind::Bytes, StreamKind::Chars) => {
+                    warnings.push(format!("{}, which only works if it is UTF-32BE", msg));
+                },
+                (StreamKind::Text, StreamKind::Chars) | (StreamKind::Chars, StreamKind::Text) => {
+                    return Err(msg);
+                },
+                // Anything can be treated as bytes, and bytes may well be text.
+                _ => (),
+            }
+            previous = Some(stage);
+            kind = output;
+        }
+        Ok(warnings)
+    }
+
+    /// Set up the encodings, chained onto the given input, and return the last one, which
+    /// yields the output of the whole pipeline. An empty pipeline passes the input through
+    /// unchanged.
+    pub fn build(&self, input: ChunkIterator) -> Result<Encoder, String> {
+        let mut input = Some(input);
+        let mut encoder: Option<Encoder> = None;
+        for stage in &self.stages {
+            debug!("encoding: {}", stage);
+            let error_policy = stage.error_policy.unwrap_or(self.error_policy);
+            let encoding = stage.instantiate_for(error_policy)
+                .map_err(|msg| format!("Error setting up {}: {}", stage.name, msg))?;
+            let source: ChunkIterator = match encoder.take() {
+                Some(previous) => Box::new(previous),
+                None => input.take().unwrap(),
+            };
+            let (input_kind, output_kind) = get_stream_kinds(&stage.name)?;
+            let mut stage_encoder = Encoder::new(source, encoding, stage.to_string(), error_policy)
+                .with_report(self.report.clone());
+            if let Some(replacement) = stage.replacement.as_ref().or(self.replacement.as_ref()) {
+                stage_encoder = if input_kind == StreamKind::Chars && output_kind != StreamKind::Chars {
+                    // Encoded as it's needed, so that it fits in with the encoding's state.
+                    stage_encoder.with_replacement_text(replacement.clone())
+                } else {
+                    stage_encoder.with_replacement(stage.replacement_output(replacement)
+                        .map_err(|msg| format!("Error setting up {}: {}", stage, msg))?)
+                };
+            }
+            encoder = Some(stage_encoder);
+        }
+
+        Ok(match encoder {
+            Some(encoder) => encoder,
+            None => Encoder::new(input.take().unwrap(), Box::new(IdentityEncoding), "identity",
+                                 self.error_policy)
+                .with_report(self.report.clone()),
+        })
+    }
+}
+
+#[test]
+fn test_pipeline_validate() {
+    let pipeline = Pipeline::parse(["un_utf8", "hex", "un_hex", "utf16"], ErrorPolicy::Halt).unwrap();
+    let warnings = pipeline.validate(StreamKind::Bytes).unwrap();
+    assert_eq!(vec!["utf16 (stage 4) expects character data (UTF-32BE), but un_hex (stage 3) \
+                     produces bytes, which only works if it is UTF-32BE"], warnings);
+
+    let pipeline = Pipeline::parse(["hex", "utf16"], ErrorPolicy::Halt).unwrap();
+    assert_eq!(Err("utf16 (stage 2) expects character data (UTF-32BE), but hex (stage 1) \
+                    produces text".to_owned()),
+               pipeline.validate(StreamKind::Bytes));
+
+    let pipeline = Pipeline::parse(["ucode", "un_ucode"], ErrorPolicy::Halt).unwrap();
+    assert!(pipeline.validate(StreamKind::Chars).unwrap().is_empty());
+    assert_eq!(1, pipeline.validate(StreamKind::Bytes).unwrap().len());
+}
+
+#[test]
+fn test_pipeline_error_position() {
+    // The lone surrogate at byte 4 of the input is an error in un_utf16,le; the error should say
+    // so, and not be re-attributed to the stages after it.
+    let pipeline = Pipeline::parse(["un_utf16,le", "utf8", "hex"], ErrorPolicy::Halt).unwrap();
+    let input = vec![Ok(b"a\0b\0\x00\xDCc\0".to_vec())];
+    let e = pipeline.build(Box::new(input.into_iter())).unwrap()
+        .filter_map(Result::err)
+        .next()
+        .unwrap();
+    assert_eq!(Some("un_utf16,le"), e.encoding_name());
+    assert_eq!(Some(4), e.offset());
+}
+
+#[test]
+fn test_pipeline_report() {
+    let pipeline = Pipeline::parse(["un_utf8", "utf8"], ErrorPolicy::Report).unwrap();
+    let input = vec![Ok(b"a\xFFb\n\xC3".to_vec())];
+    let output: Vec<u8> = pipeline.build(Box::new(input.into_iter())).unwrap()
+        .flat_map(Result::unwrap)
+        .collect();
+    assert_eq!("a\u{FFFD}b\n\u{FFFD}".as_bytes(), output.as_slice());
+    assert_eq!(2, pipeline.report().len());
+    let summary = pipeline.report().to_string();
+    assert!(summary.starts_with("2 errors:\n"));
+    assert!(summary.contains("un_utf8: 2"));
+    let errors = pipeline.report().take();
+    assert_eq!(Some(1), errors[0].offset());
+    assert_eq!(Some((2, 1)), errors[1].position().and_then(|position| position.line_column));
+}
+
+#[test]
+fn test_stage_error_policy() {
+    let stage = Stage::parse("iso8859,errors=skip,1").unwrap();
+    assert_eq!("iso8859", stage.name);
+    assert_eq!("1", stage.options);
+    assert_eq!(Some(ErrorPolicy::Skip), stage.error_policy);
+    assert!(Stage::parse("un_utf8,errors=maybe").is_err());
+
+    // Bad input gets replaced, but the replacement character can't be encoded.
+    let pipeline = Pipeline::parse(["un_utf8,errors=replace", "iso8859,1"], ErrorPolicy::Halt)
+        .unwrap();
+    let input = vec![Ok(b"caf\xC3\xA9 \xFF".to_vec())];
+    let results: Vec<_> = pipeline.build(Box::new(input.into_iter())).unwrap().collect();
+    assert_eq!(b"caf\xE9 ", results[0].as_ref().unwrap().as_slice());
+    assert_eq!(Some("iso8859,1"), results[1].as_ref().unwrap_err().encoding_name());
+
+    // An error that halts one stage isn't replaced by a later one.
+    let pipeline = Pipeline::parse(["un_utf8", "utf8,errors=replace"], ErrorPolicy::Halt)
+        .unwrap();
+    let input = vec![Ok(b"a\xFF".to_vec())];
+    let results: Vec<_> = pipeline.build(Box::new(input.into_iter())).unwrap().collect();
+    assert_eq!(b"a", results[0].as_ref().unwrap().as_slice());
+    assert_eq!(Some("un_utf8"), results[1].as_ref().unwrap_err().encoding_name());
+    assert_eq!(2, results.len());
+}
+
+#[test]
+fn test_forced_error_policy() {
+    // As for --check: a stage's own policy doesn't hide the error.
+    let pipeline = Pipeline::parse(["un_utf8,errors=replace", "utf8"], ErrorPolicy::Skip).unwrap()
+        .with_forced_error_policy(ErrorPolicy::Halt);
+    let input = vec![Ok(b"a\xFFb".to_vec())];
+    let results: Vec<_> = pipeline.build(Box::new(input.into_iter())).unwrap().collect();
+    let error = results.iter().find_map(|result| result.as_ref().err()).unwrap();
+    assert_eq!(Some("un_utf8"), error.encoding_name());
+
+    // And as for --check=all: every error is counted.
+    let pipeline = Pipeline::parse(["un_utf8,errors=skip", "utf8,errors=replace"], ErrorPolicy::Halt)
+        .unwrap()
+        .with_forced_error_policy(ErrorPolicy::Report);
+    let input = vec![Ok(b"a\xFFb\xFE".to_vec())];
+    assert!(pipeline.build(Box::new(input.into_iter())).unwrap().all(|result| result.is_ok()));
+    assert_eq!(2, pipeline.report().len());
+}
+
+#[test]
+fn test_replacement() {
+    assert_eq!(Ok("<?>\u{bf},\u{1F600}\\".to_owned()), unescape(r"<?>\xBF\x2C\u{1f600}\\"));
+    assert!(unescape(r"\q").is_err());
+    assert!(unescape(r"\u{D800}").is_err());
+
+    let pipeline = Pipeline::parse(["un_utf8,replace=<?>", "iso8859,1,replace=\\xBF"],
+                                   ErrorPolicy::Replace).unwrap();
+    let input = vec![Ok(b"\xE2\x82\xAC1 \xFF".to_vec())];
+    let output: Vec<u8> = pipeline.build(Box::new(input.into_iter())).unwrap()
+        .flat_map(Result::unwrap)
+        .collect();
+    assert_eq!(b"\xBF1 <?>", output.as_slice());
+
+    // A global replacement is encoded for each stage separately.
+    let pipeline = Pipeline::parse(["un_utf8", "utf16,be"], ErrorPolicy::Replace).unwrap()
+        .with_replacement("*".to_owned());
+    let input = vec![Ok(b"\xFF".to_vec())];
+    let output: Vec<u8> = pipeline.build(Box::new(input.into_iter())).unwrap()
+        .flat_map(Result::unwrap)
+        .collect();
+    assert_eq!(b"\0*", output.as_slice());
+
+    assert!(Pipeline::parse(["iso8859,1,replace=\\u{20AC}"], ErrorPolicy::Replace).is_err());
+}
+
+#[test]
+fn test_escaping_error_policies() {
+    let convert = |specs: &[&str], policy, input: &[u8]| -> Vec<u8> {
+        let pipeline = Pipeline::parse(specs, policy).unwrap();
+        let input = vec![Ok(input.to_vec())];
+        pipeline.build(Box::new(input.into_iter())).unwrap()
+            .flat_map(Result::unwrap)
+            .collect()
+    };
+    let input = "caf\u{E9} \u{1F600}\u{20AC}".as_bytes();
+    assert_eq!(b"caf\xE9 &#x1F600;&#x20AC;".to_vec(),
+               convert(&["un_utf8", "iso8859,1"], ErrorPolicy::XmlCharRefReplace, input));
+    assert_eq!(b"caf\xE9 \\U0001f600\x80".to_vec(),
+               convert(&["un_utf8", "windows1252"], ErrorPolicy::BackslashReplace, input));
+    assert_eq!(b"caf\x82 \\N{GRINNING FACE}\\N{EURO SIGN}".to_vec(),
+               convert(&["un_utf8", "cp437"], ErrorPolicy::NameReplace, input));
+
+    // Invalid input gets its bytes escaped.
+    assert_eq!(b"a\\xff\\xc3b".to_vec(),
+               convert(&["un_utf8", "utf8"], ErrorPolicy::BackslashReplace, b"a\xFF\xC3b"));
+}
+
+#[test]
+fn test_surrogateescape() {
+    let input = b"caf\xC3\xA9 \xFF\xC3(\xE9)".to_vec();
+    let pipeline = Pipeline::parse(["un_utf8", "utf8"], ErrorPolicy::SurrogateEscape).unwrap();
+    // Split the input up to check that escapes are found across chunk boundaries.
+    let chunks: Vec<_> = input.chunks(3).map(|chunk| Ok(chunk.to_vec())).collect();
+    let output: Vec<u8> = pipeline.build(Box::new(chunks.into_iter())).unwrap()
+        .flat_map(Result::unwrap)
+        .collect();
+    assert_eq!(input, output);
+
+    let pipeline = Pipeline::parse(["un_utf8", "ucode"], ErrorPolicy::SurrogateEscape).unwrap();
+    let output: Vec<u8> = pipeline.build(Box::new(Some(Ok(input)).into_iter())).unwrap()
+        .flat_map(Result::unwrap)
+        .collect();
+    assert!(String::from_utf8(output).unwrap().contains("U+DCFF"));
+
+    // Only bytes that aren't ASCII are escaped, and un-escaped.
+    let pipeline = Pipeline::parse(["un_hex"], ErrorPolicy::SurrogateEscape).unwrap();
+    assert!(pipeline.build(Box::new(Some(Ok(b"41zz".to_vec())).into_iter())).unwrap()
+        .any(|r| r.is_err()));
+    let pipeline = Pipeline::parse(["iso8859,1"], ErrorPolicy::SurrogateEscape).unwrap();
+    let input = vec![0, 0, 0xDC, 0xFF, 0, 0, 0xDC, 0x41];
+    let results: Vec<_> = pipeline.build(Box::new(Some(Ok(input)).into_iter())).unwrap().collect();
+    assert_eq!(b"\xFF", results[0].as_ref().unwrap().as_slice());
+    assert!(results[1].is_err());
+
+    // Any bytes at all survive the round trip, including overlong sequences and encoded
+    // surrogates, which would otherwise decode to real characters.
+    let mut input = b"\xC1\xA1\xED\xB2\x80\xE0\x80\xAF\xF4\x90\x80\x80\xF8\x88\x80\x80\x80".to_vec();
+    let mut state = 12345u32;
+    for _ in 0 .. 100_000 {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        input.push((state >> 16) as u8);
+    }
+    let pipeline = Pipeline::parse(["un_utf8", "utf8"], ErrorPolicy::SurrogateEscape).unwrap();
+    let chunks: Vec<_> = input.chunks(1000).map(|chunk| Ok(chunk.to_vec())).collect();
+    let output: Vec<u8> = pipeline.build(Box::new(chunks.into_iter())).unwrap()
+        .flat_map(Result::unwrap)
+        .collect();
+    assert!(input == output, "random bytes didn't survive the round trip");
+    assert!(Pipeline::parse(["un_utf8,relaxed", "utf8"], ErrorPolicy::SurrogateEscape).is_err());
+    assert!(Pipeline::parse(["un_utf8,relaxed,errors=surrogateescape"], ErrorPolicy::Halt).is_err());
+
+    // Characters that can't be encoded aren't escapes.
+    let pipeline = Pipeline::parse(["un_utf8", "iso8859,1"], ErrorPolicy::SurrogateEscape).unwrap();
+    let input = "\u{20AC}".as_bytes().to_vec();
+    assert!(pipeline.build(Box::new(Some(Ok(input)).into_iter())).unwrap().any(|r| r.is_err()));
+}